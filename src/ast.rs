@@ -14,6 +14,39 @@ pub struct FragmentAST {
     pub assignments: Vec<AssignmentAST>,
     pub submodules: Vec<FragmentAST>,
     pub output: Expression,
+    /// Whether applications of the fragment may be memoized (`nomemo` opts out).
+    pub memoize: bool,
+    /// Claims declared with `assert` or `invariant`, which should always
+    /// evaluate to true.
+    pub assertions: Vec<ClaimAST>,
+    /// Names of the assignments declared with `out`, which the main module
+    /// exposes as outputs to the host.
+    pub outputs: Vec<Name>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ClaimKind {
+    /// A claim checked whenever the module's value is computed.
+    Assert,
+    /// A claim about state that is kept across ticks, like `len(buffer) <= 100`.
+    /// It is checked after every update while the module is instantiated,
+    /// whether or not anything reads the module's value.
+    Invariant,
+}
+
+impl ClaimKind {
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            ClaimKind::Assert => "assert",
+            ClaimKind::Invariant => "invariant",
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct ClaimAST {
+    pub kind: ClaimKind,
+    pub claim: Expression,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -22,14 +55,22 @@ pub struct ModuleInput {
     pub input_type: Type,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum BinaryOp {
     Sum,Geq,Leq,Eq,Lt,Gt,Concat,Index,Range
 }
 
+/// A piece of an interpolated string literal.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum StringPart {
+    Literal(String),
+    Interpolated(Expression),
+}
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Expression {
     ConstString(String),
+    Interpolation(Vec<StringPart>),
     ConstInteger(i64),
     ConstBoolean(bool),
     BinaryOp(Box<Expression>, Box<Expression>, BinaryOp),
@@ -43,6 +84,11 @@ pub enum Expression {
         body: Box<Expression>,
         else_body: Box<Expression>,
     },
+    /// Local bindings, visible to later bindings and to the body.
+    Let {
+        bindings: Vec<AssignmentAST>,
+        body: Box<Expression>,
+    },
 }
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug)]
@@ -55,6 +101,13 @@ impl Expression {
     pub fn collect_dependencies(&self) -> Vec<Dependency> {
         match self {
             Expression::ConstString(_) => vec![],
+            Expression::Interpolation(parts) => parts
+                .iter()
+                .flat_map(|part| match part {
+                    StringPart::Literal(_) => vec![],
+                    StringPart::Interpolated(e) => e.collect_dependencies(),
+                })
+                .collect(),
             Expression::ConstInteger(_) => vec![],
             Expression::ConstBoolean(_) => vec![],
             Expression::BinaryOp(a, b, _) => a
@@ -83,6 +136,35 @@ impl Expression {
                 .chain(body.collect_dependencies().into_iter())
                 .chain(else_body.collect_dependencies().into_iter())
                 .collect(),
+            Expression::Let { bindings, body } => {
+                // Names bound by the let are local, so they are not
+                // dependencies on the enclosing module.
+                let mut bound: Vec<&str> = Vec::new();
+                let mut deps = Vec::new();
+
+                let is_free = |dep: &Dependency, bound: &[&str]| match dep {
+                    Dependency::Value(n) | Dependency::Module(n) => !bound.contains(&n.as_str()),
+                };
+
+                for binding in bindings {
+                    deps.extend(
+                        binding
+                            .expr
+                            .collect_dependencies()
+                            .into_iter()
+                            .filter(|d| is_free(d, &bound)),
+                    );
+                    bound.push(&binding.name.0);
+                }
+
+                deps.extend(
+                    body.collect_dependencies()
+                        .into_iter()
+                        .filter(|d| is_free(d, &bound)),
+                );
+
+                deps
+            }
         }
     }
 }
@@ -94,6 +176,8 @@ pub struct TypeDecl(pub Name, pub Type);
 pub enum Type {
     PrimInt,
     PrimString,
+    PrimBool,
+    PrimChar,
 }
 
 #[derive(Hash, Debug, Eq, PartialEq, Clone)]
@@ -3,12 +3,14 @@
 /// be executed.
 use crate::ast::*;
 use crate::program::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::rc::Rc;
 use topological_sort::TopologicalSort;
 
 pub mod fragment_builder;
 use crate::run::RuntimeEnv;
+use crate::code_from_ast::TempuraAST;
+use crate::verifier::{verify_claim, verify_index, Verdict, VerifyResult};
 use fragment_builder::*;
 
 trait Named {
@@ -43,19 +45,38 @@ fn index_named<T: Named>(assignments: Vec<T>) -> Result<HashMap<String, T>, &'st
     Ok(assignments_astnodes)
 }
 
-fn build_value(expr: Expression, env: &mut FragmentBuilder) -> Result<LacunaryRef, &'static str> {
+pub(crate) fn build_value(expr: Expression, env: &mut FragmentBuilder) -> Result<LacunaryRef, &'static str> {
     use Operation::*;
     // use compute::VarType;
 
     Ok(match expr {
-        Expression::ConstInteger(i) => env.alloc_value(Operation::Const(VarType::Int(i))),
-        Expression::ConstBoolean(b) => env.alloc_value(Operation::Const(VarType::Bool(b))),
-        Expression::ConstString(s) => {
-            let charvec = s
-                .chars()
-                .map(|c| env.alloc_value(Const(VarType::Char(c))))
-                .collect();
-            env.alloc_value(Vector(charvec))
+        Expression::ConstInteger(i) => env.alloc_value(Operation::Const(VarType::Int(i)))?,
+        Expression::ConstBoolean(b) => env.alloc_value(Operation::Const(VarType::Bool(b)))?,
+        Expression::ConstString(s) => env.alloc_value(Const(VarType::from_string(&s)))?,
+        Expression::Interpolation(parts) => {
+            // Desugar into a chain of concatenations of the literal text
+            // and the rendered interpolated values.
+            let mut acc: Option<LacunaryRef> = None;
+
+            for part in parts {
+                let part_idx = match part {
+                    StringPart::Literal(s) => env.alloc_value(Const(VarType::from_string(&s)))?,
+                    StringPart::Interpolated(e) => {
+                        let e_idx = build_value(e, env)?;
+                        env.alloc_value(ToString(e_idx))?
+                    }
+                };
+
+                acc = Some(match acc {
+                    Some(a) => env.alloc_value(BinaryOp(a, part_idx, crate::ast::BinaryOp::Concat))?,
+                    None => part_idx,
+                });
+            }
+
+            match acc {
+                Some(a) => a,
+                None => env.alloc_value(Const(VarType::from_string("")))?,
+            }
         },
         Expression::LacunaryRef(Name(n)) => match env.lookup_value(&n) {
             Some(r) => r,
@@ -72,7 +93,7 @@ fn build_value(expr: Expression, env: &mut FragmentBuilder) -> Result<LacunaryRe
 
             let fragref = env.lookup_value(&mod_name.0).expect("module not found");
 
-            env.alloc_value(Operation::ApplyFragment(fragref, argrefs))
+            env.alloc_value(Operation::ApplyFragment(fragref, argrefs))?
         },
         Expression::IfElse {
             guard,
@@ -83,17 +104,135 @@ fn build_value(expr: Expression, env: &mut FragmentBuilder) -> Result<LacunaryRe
             let body_idx = build_value(*body, env)?;
             let else_idx = build_value(*else_body, env)?;
 
-            env.alloc_value(IfElse(guard_idx, body_idx, else_idx))
+            env.alloc_value(IfElse(guard_idx, body_idx, else_idx))?
+        },
+        Expression::Let { bindings, body } => {
+            // Bindings shadow whatever the names referred to before, and are
+            // visible to later bindings and the body. The previous entries are
+            // put back afterwards so the names don't leak out of the let.
+            let mut shadowed = Vec::new();
+
+            let result = bind_locals(bindings, env, &mut shadowed)
+                .and_then(|_| build_value(*body, env));
+
+            for (name, previous) in shadowed.into_iter().rev() {
+                match previous {
+                    Some(p) => env.values_by_name.insert(name, p),
+                    None => env.values_by_name.remove(&name),
+                };
+            }
+
+            result?
         },
         Expression::BinaryOp(a,b,op) => {
+            let code = match op {
+                crate::ast::BinaryOp::Index => Some((a.gen_code(), b.gen_code())),
+                _ => None,
+            };
             let a_idx = build_value(*a, env)?;
             let b_idx = build_value(*b, env)?;
 
-            env.alloc_value(BinaryOp(a_idx, b_idx, op))
+            if let Some(code) = code {
+                check_index(env, &a_idx, &b_idx, code);
+            }
+
+            env.alloc_value(BinaryOp(a_idx, b_idx, op))?
         }
     })
 }
 
+/// Warn about indexing that can't be shown to stay within bounds, suggesting
+/// the `get` and `clamp` builtins instead.
+fn check_index(env: &FragmentBuilder, container: &LacunaryRef, index: &LacunaryRef, code: (String, String)) {
+    let verdict = verify_index(env, container, index);
+    let (container, index) = code;
+
+    match verdict.result {
+        VerifyResult::True => {}
+        VerifyResult::False => env.warn(format!(
+            "Index `{}[{}]` is always out of bounds: {}.",
+            container, index, verdict.reason
+        )),
+        VerifyResult::Unsure => env.warn(format!(
+            "Index `{}[{}]` may be out of bounds: {}. Consider `get({}, {}, default)`, \
+             or limiting the index with `clamp`.",
+            container, index, verdict.reason, container, index
+        )),
+    }
+}
+
+/// Build the bindings of a let in order, making each one visible under its name.
+/// The entries they replace are pushed onto `shadowed` so they can be restored.
+fn bind_locals(
+    bindings: Vec<AssignmentAST>,
+    env: &mut FragmentBuilder,
+    shadowed: &mut Vec<(String, Option<LacunaryRef>)>,
+) -> Result<(), &'static str> {
+    for binding in bindings {
+        let name = binding.name.0;
+
+        if shadowed.iter().any(|(n, _)| *n == name) {
+            return Err("Duplicate binding in let.");
+        }
+
+        let val = build_value(binding.expr, env)?;
+        let previous = env.values_by_name.insert(name.clone(), val);
+        shadowed.push((name, previous));
+    }
+
+    Ok(())
+}
+
+/// Build a named value or submodule unless it was built already.
+fn build_dependency(
+    dep: Dependency,
+    fb: &mut FragmentBuilder,
+    ast_index: &mut HashMap<String, AssignmentAST>,
+    mod_index: &mut HashMap<String, FragmentAST>,
+) -> Result<(), &'static str> {
+    match dep {
+        Dependency::Module(modname) => {
+            if fb.lookup_value(&modname).is_none() {
+                let modl = mod_index.remove(modname.as_str()).unwrap();
+                let frag = build_module(modl, fb)?;
+                let fref = fb.alloc_fragment(frag)?;
+                fb.values_by_name.insert(modname, fref);
+            }
+        }
+        Dependency::Value(valname) => {
+            if fb.lookup_value(&valname).is_none() {
+                let val = ast_index.remove(valname.as_str()).unwrap();
+                let val_built = build_value(val.expr, fb)?;
+                fb.values_by_name.insert(valname, val_built);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Build the claim of an `assert` or `invariant` and check it statically,
+/// rejecting claims that can never hold. Claims the verifier can't decide are
+/// accepted, to be checked at runtime.
+pub fn check_assertion(
+    claim: Expression,
+    env: &mut FragmentBuilder,
+) -> Result<(LacunaryRef, Verdict), &'static str> {
+    let claim = build_value(claim, env)?;
+
+    match env.type_of(&claim) {
+        Some(Type::PrimBool) | None => {}
+        Some(_) => return Err("Assertion must be a boolean."),
+    }
+
+    let verdict = verify_claim(env, &claim);
+
+    match verdict.result {
+        VerifyResult::False => Err("Assertion can never hold."),
+        _ => Ok((claim, verdict)),
+    }
+}
+
 pub fn build_module(
     modu: FragmentAST,
     parent_env: &FragmentBuilder,
@@ -102,10 +241,12 @@ pub fn build_module(
     let mut mod_index = index_named(modu.submodules)?;
 
     let mut fb = parent_env.derive_child(modu.name.0);
+    fb.memoize = modu.memoize;
 
     for (index, mi) in modu.inputs.iter().enumerate() {
         fb.values_by_name
             .insert(mi.name.0.to_string(), LacunaryRef::InputRef { up: 0, index });
+        fb.input_types.push(mi.input_type.clone());
     }
 
     let mut ts = TopologicalSort::<Dependency>::new();
@@ -117,56 +258,159 @@ pub fn build_module(
     }
 
     while let Some(dep) = ts.pop() {
-        match dep {
-            Dependency::Module(modname) => {
-                if fb.lookup_value(&modname).is_none() {
-                    let modl = mod_index.remove(modname.as_str()).unwrap();
-                    let frag = build_module(modl, &fb)?;
-                    let fref = fb.alloc_fragment(frag);
-                    fb.values_by_name.insert(modname, fref);
-                }
-            }
-            Dependency::Value(valname) => {
-                if fb.lookup_value(&valname).is_none() {
-                    let val = ast_index.remove(valname.as_str()).unwrap();
-                    let val_built = build_value(val.expr, &mut fb)?;
-                    fb.values_by_name.insert(valname, val_built);
-                }
-            }
-        }
+        build_dependency(dep, &mut fb, &mut ast_index, &mut mod_index)?;
     }
 
     for dep in modu.output.collect_dependencies() {
-        match dep {
-            Dependency::Module(modname) => {
-                if fb.lookup_value(&modname).is_none() {
-                    let modl = mod_index.remove(modname.as_str()).unwrap();
-                    let frag = build_module(modl, &fb)?;
-                    let fref = fb.alloc_fragment(frag);
-                    fb.values_by_name.insert(modname, fref);
-                }
+        build_dependency(dep, &mut fb, &mut ast_index, &mut mod_index)?;
+    }
+
+    for name in modu.outputs {
+        build_dependency(Dependency::Value(name.0.clone()), &mut fb, &mut ast_index, &mut mod_index)?;
+        let value = fb.lookup_value(&name.0).unwrap();
+        fb.outputs.insert(name.0, value);
+    }
+
+    let mut checks = Vec::new();
+
+    for ClaimAST { kind, claim } in modu.assertions {
+        let deps = claim.collect_dependencies();
+        for dep in deps.iter().cloned() {
+            build_dependency(dep, &mut fb, &mut ast_index, &mut mod_index)?;
+        }
+
+        let source = claim.gen_code();
+        let (claim_ref, verdict) = check_assertion(claim, &mut fb)?;
+
+        if verdict.result == VerifyResult::Unsure {
+            let mut operands: Vec<(String, LacunaryRef)> = deps
+                .into_iter()
+                .filter_map(|dep| match dep {
+                    Dependency::Value(name) => fb.lookup_value(&name).map(|r| (name, r)),
+                    Dependency::Module(_) => None,
+                })
+                .collect();
+            operands.sort();
+            operands.dedup();
+
+            let (names, refs) = operands.into_iter().unzip();
+            checks.push((Claim { kind, source, operands: names }, claim_ref, refs));
+        }
+    }
+
+    // Asserts that couldn't be verified are checked whenever the output is
+    // computed, by nodes that pass the output on. Invariants get nodes of
+    // their own, which the runtime checks after every update.
+    let mut output = build_value(modu.output, &mut fb)?;
+
+    for (claim, claim_ref, refs) in checks {
+        match claim.kind {
+            ClaimKind::Assert => {
+                output = fb.alloc_value(Operation::Check(Rc::new(claim), claim_ref, refs, output))?;
             }
-            Dependency::Value(valname) => {
-                if fb.lookup_value(&valname).is_none() {
-                    let val = ast_index.remove(valname.as_str()).unwrap();
-                    let val_built = build_value(val.expr, &mut fb)?;
-                    fb.values_by_name.insert(valname, val_built);
-                }
+            ClaimKind::Invariant => {
+                fb.alloc_value(Operation::Check(Rc::new(claim), claim_ref, refs, claim_ref))?;
             }
         }
     }
 
-    let output = build_value(modu.output, &mut fb)?;
-
     Ok(fb.build(output))
 }
 
 pub fn build_runtime(main_module: FragmentAST) -> Result<RuntimeEnv, &'static str> {
-    let stdlib = vec![
+    build_runtime_with(main_module, &[], &[])
+}
+
+/// Build a runtime for a program that can refer to external inputs with the
+/// given names, besides `stdin` and `clock`. The parameters of the main module
+/// are bound to the inputs with their names, which are declared if needed,
+/// and only take values of the parameter's type, starting with its default.
+/// Other inputs are null until a value is put on them. The native functions can be
+/// called by name like the stdlib.
+pub fn build_runtime_with(
+    main_module: FragmentAST,
+    inputs: &[&str],
+    natives: &[Rc<NativeFunction>],
+) -> Result<RuntimeEnv, &'static str> {
+    let mut re = RuntimeEnv::new();
+    let mut fb = prelude(&mut re);
+
+    for native in natives {
+        register_native(&mut re, &mut fb, native.clone())?;
+    }
+
+    for name in inputs {
+        if fb.values_by_name.contains_key(*name) {
+            return Err("Input name is already taken.");
+        }
+        let input = re.declare_input(name);
+        fb.values_by_name
+            .insert(name.to_string(), LacunaryRef::InstanciatedRef(input));
+    }
+
+    let mut arguments = Vec::new();
+    for mi in main_module.inputs.iter() {
+        let input = match re.input(&mi.name.0) {
+            Some(input) => input,
+            None => re.declare_input(&mi.name.0),
+        };
+        re.set_input_type(input, mi.input_type.clone());
+        arguments.push(input);
+    }
+
+    let mainmod = build_module(main_module, &fb)?;
+
+    re.instantiate_main(&mainmod, arguments);
+    re.warnings = fb.take_warnings();
+
+    Ok(re)
+}
+
+/// Make a native function available under its name, as a fragment that calls it.
+fn register_native(
+    re: &mut RuntimeEnv,
+    fb: &mut FragmentBuilder,
+    native: Rc<NativeFunction>,
+) -> Result<(), &'static str> {
+    if fb.values_by_name.contains_key(&native.name) {
+        return Err("Native function name is already taken.");
+    }
+
+    let name = native.name.clone();
+    let signature = native.signature.clone();
+    let frag = Fragment {
+        name: name.clone(),
+        nodes: vec![Operation::Native(
+            native,
+            (0..signature.inputs.len())
+                .map(|index| LacunaryRef::InputRef { up: 0, index })
+                .collect(),
+        )],
+        output: LacunaryRef::ContextRef { up: 0, index: 0 },
+        memoize: true,
+        names: BTreeMap::new(),
+        outputs: BTreeMap::new(),
+    };
+
+    let n = re.node_from_operation(Operation::Const(VarType::Fragment(Rc::new(frag))));
+    re.set_stable_id(n, &name);
+    fb.values_by_name.insert(name, LacunaryRef::InstanciatedRef(n));
+    fb.declare_signature(LacunaryRef::InstanciatedRef(n), signature);
+
+    Ok(())
+}
+
+/// Create the builtin fragments and inputs in `re`, and a root environment
+/// that names them.
+pub fn prelude(re: &mut RuntimeEnv) -> FragmentBuilder<'static> {
+    let mut stdlib = vec![
         Fragment {
             name: "to_string".to_string(),
             nodes: vec![Operation::ToString(LacunaryRef::InputRef { up: 0, index: 0 })],
             output: LacunaryRef::ContextRef { up: 0, index: 0 },
+            memoize: true,
+            names: BTreeMap::new(),
+            outputs: BTreeMap::new(),
         },
         Fragment {
             name: "concat".to_string(),
@@ -176,36 +420,85 @@ pub fn build_runtime(main_module: FragmentAST) -> Result<RuntimeEnv, &'static st
                 BinaryOp::Concat
             )],
             output: LacunaryRef::ContextRef { up: 0, index: 0 },
+            memoize: true,
+            names: BTreeMap::new(),
+            outputs: BTreeMap::new(),
         },
     ];
 
-    let mut re = RuntimeEnv::new();
+    stdlib.push(Fragment {
+        name: "sort".to_string(),
+        nodes: vec![Operation::Sort(LacunaryRef::InputRef { up: 0, index: 0 })],
+        output: LacunaryRef::ContextRef { up: 0, index: 0 },
+        memoize: true,
+        names: BTreeMap::new(),
+        outputs: BTreeMap::new(),
+    });
+
+    for op in StringOp::ALL.iter() {
+        stdlib.push(Fragment {
+            name: op.name().to_string(),
+            nodes: vec![Operation::StringOp(
+                *op,
+                (0..op.arity())
+                    .map(|index| LacunaryRef::InputRef { up: 0, index })
+                    .collect(),
+            )],
+            output: LacunaryRef::ContextRef { up: 0, index: 0 },
+            memoize: true,
+            names: BTreeMap::new(),
+            outputs: BTreeMap::new(),
+        });
+    }
+
     let mut fb = FragmentBuilder::new("".to_string());
 
     for f in stdlib {
         let name = f.name.clone();
         let n = re.node_from_operation(Operation::Const(VarType::Fragment(Rc::new(f))));
+        re.set_stable_id(n, &name);
         fb.values_by_name.insert(name, LacunaryRef::InstanciatedRef(n));
     }
 
-    let stdin = re.node_from_operation(Operation::External);
+    // Effects aren't memoized, since asking for the same effect again may
+    // give a different result, e.g. when a file changed.
+    for kind in EffectKind::ALL.iter() {
+        let frag = Fragment {
+            name: kind.name().to_string(),
+            nodes: vec![Operation::Effect(*kind, vec![LacunaryRef::InputRef { up: 0, index: 0 }])],
+            output: LacunaryRef::ContextRef { up: 0, index: 0 },
+            memoize: false,
+            names: BTreeMap::new(),
+            outputs: BTreeMap::new(),
+        };
+        let n = re.node_from_operation(Operation::Const(VarType::Fragment(Rc::new(frag))));
+        re.set_stable_id(n, kind.name());
+        fb.values_by_name
+            .insert(kind.name().to_string(), LacunaryRef::InstanciatedRef(n));
+        fb.declare_signature(LacunaryRef::InstanciatedRef(n), kind.signature());
+    }
+
+    let stdin = re.declare_input("stdin");
     fb.values_by_name
         .insert("stdin".to_string(), LacunaryRef::InstanciatedRef(stdin));
 
-    let clock = re.node_from_operation(Operation::External);
+    let clock = re.declare_input("clock");
     re.put_current(clock, VarType::Int(0));
     fb.values_by_name
         .insert("clock".to_string(), LacunaryRef::InstanciatedRef(clock));
 
-    let mainmod = build_module(main_module, &fb).unwrap();
-
-    let stdout = re.instantiate_fragment(&mainmod, vec![]);
+    // Milliseconds since the program started, and since the Unix epoch, as
+    // of the last tick of the clock. Both are null until the host puts the
+    // time on them, which the CLI does at startup.
+    let millis = re.declare_input("millis");
+    fb.values_by_name
+        .insert("millis".to_string(), LacunaryRef::InstanciatedRef(millis));
 
-    re.stdout = Some(stdout);
-    re.stdin = Some(stdin);
-    re.clock = Some(clock);
+    let wallclock = re.declare_input("wallclock");
+    fb.values_by_name
+        .insert("wallclock".to_string(), LacunaryRef::InstanciatedRef(wallclock));
 
-    Ok(re)
+    fb
 }
 
 #[cfg(test)]
@@ -223,4 +516,170 @@ mod tests {
 
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_branch_types_agree() {
+        let mut env = FragmentBuilder::new("test_environment".to_string());
+
+        let chain = r#"if true then 1 elif false then "two" else 3"#;
+        assert!(build_value(prs::expression::<VerboseError<&str>>(chain).unwrap().1, &mut env).is_err());
+
+        let chain = r#"cond { false => 1, true => 2, else => 3 }"#;
+        assert!(build_value(prs::expression::<VerboseError<&str>>(chain).unwrap().1, &mut env).is_ok());
+    }
+
+    #[test]
+    fn test_interpolation_recomputes() {
+        let src = r#"mod main() {
+            "tick: ${clock}!"
+        }"#;
+
+        let modu = prs::parse_tempura::<VerboseError<&str>>(src).unwrap().1;
+        let mut rte = build_runtime(modu).unwrap();
+        let stdout = rte.output("stdout").unwrap();
+
+        assert_eq!(rte.pull_once(stdout).stringify(), Some("tick: 0!".to_string()));
+
+        rte.put_current(rte.input("clock").unwrap(), VarType::Int(42));
+
+        assert_eq!(rte.pull_once(stdout).stringify(), Some("tick: 42!".to_string()));
+    }
+
+    #[test]
+    fn test_let_shadowing() {
+        let src = r#"mod main() {
+            a = 1
+            let a = 2; b = a + a in b + a
+        }"#;
+
+        let modu = prs::parse_tempura::<VerboseError<&str>>(src).unwrap().1;
+        let mut rte = build_runtime(modu).unwrap();
+
+        assert_eq!(rte.pull_once(rte.output("stdout").unwrap()).unpack_int(), Some(6));
+    }
+
+    #[test]
+    fn test_let_duplicate_binding() {
+        let mut env = FragmentBuilder::new("test_environment".to_string());
+
+        let expr = prs::expression::<VerboseError<&str>>("let a = 1; a = 2 in a").unwrap().1;
+
+        assert!(build_value(expr, &mut env).is_err());
+        assert!(env.values_by_name.get("a").is_none());
+    }
+
+    #[test]
+    fn test_structural_comparison() {
+        let src = r#"mod main() {
+            sorted = sort(split("pear,apple,fig", ","))
+            expected = split("apple,fig,pear", ",")
+            ordered = "apple" < "banana"
+            if sorted == expected then ordered else false
+        }"#;
+
+        let modu = prs::parse_tempura::<VerboseError<&str>>(src).unwrap().1;
+        let mut rte = build_runtime(modu).unwrap();
+
+        assert_eq!(rte.pull_once(rte.output("stdout").unwrap()), VarType::Bool(true));
+    }
+
+    #[test]
+    fn test_string_literal_single_node() {
+        let src = r#"mod main() {
+            t = "Suspendisse venenatis feugiat tempus."
+            t[3]
+        }"#;
+
+        let modu = prs::parse_tempura::<VerboseError<&str>>(src).unwrap().1;
+        let env = FragmentBuilder::new("test_environment".to_string());
+
+        // One node for the literal, one for the index and one for the
+        // subscript; the literal used to take one node per character.
+        assert_eq!(build_module(modu, &env).unwrap().nodes.len(), 3);
+    }
+
+    #[test]
+    fn test_module_assertions() {
+        let build = |claim: &str| {
+            let src = format!(
+                "mod main(x : int) {{\n c = clamp(x, 0, 100)\n total = c + 1\n assert {}\n total\n}}",
+                claim
+            );
+            build_runtime(prs::parse_tempura::<VerboseError<&str>>(&src).unwrap().1).map(|_| ())
+        };
+
+        assert_eq!(build("total > c"), Ok(()));
+        assert_eq!(build("total >= 0"), Ok(()));
+        assert_eq!(build("total == c"), Err("Assertion can never hold."));
+    }
+
+    #[test]
+    fn test_index_warnings() {
+        let warnings = |output: &str| {
+            let src = format!("mod main() {{\n t = \"tempura\"\n {}\n}}", output);
+            build_runtime(prs::parse_tempura::<VerboseError<&str>>(&src).unwrap().1)
+                .unwrap()
+                .warnings
+        };
+
+        assert!(warnings("t[3]").is_empty());
+        assert!(warnings("t[clamp(clock, 0, 6)]").is_empty());
+        assert!(warnings("get(t, clock, \" \")").is_empty());
+
+        let w = warnings("t[clock]");
+        assert_eq!(w.len(), 1);
+        assert!(w[0].contains("`t[clock]` may be out of bounds"));
+        assert!(w[0].contains("get(t, clock, default)"));
+
+        assert!(warnings("t[7]")[0].contains("always out of bounds"));
+    }
+
+    #[test]
+    fn test_runtime_checks() {
+        let src = r#"mod main() {
+            total = clock + 1
+            assert total > clock
+            invariant total <= 3
+            total
+        }"#;
+
+        let mut rte = build_runtime(prs::parse_tempura::<VerboseError<&str>>(src).unwrap().1).unwrap();
+        let (stdout, clock) = (rte.output("stdout").unwrap(), rte.input("clock").unwrap());
+
+        assert_eq!(rte.pull_once(stdout), VarType::Int(1));
+        assert_eq!(rte.take_errors(), vec![]);
+
+        rte.put_current(clock, VarType::Int(5));
+        assert_eq!(rte.pull_once(stdout), VarType::Int(6));
+
+        // Only the claim that couldn't be verified statically is checked.
+        let errors = rte.take_errors();
+        assert_eq!(errors.len(), 1);
+        let message = errors[0].to_string();
+        assert!(message.starts_with(&format!("at tick {}: invariant `total <= 3` failed", rte.current_time())));
+        assert!(message.ends_with("with total = 6"));
+    }
+
+    #[test]
+    fn test_checks_run_unread() {
+        let src = r#"mod main() {
+            out total = clock + 1
+            assert total > clock
+            invariant total <= 3
+            assert total <= 4
+            0
+        }"#;
+
+        let mut rte = build_runtime(prs::parse_tempura::<VerboseError<&str>>(src).unwrap().1).unwrap();
+        let clock = rte.input("clock").unwrap();
+
+        // Nothing reads stdout, which the asserts wrap, or even `total`.
+        rte.put_current(clock, VarType::Int(4));
+        let failed: Vec<String> = rte.take_errors().iter().map(|e| e.to_string()).collect();
+        assert_eq!(failed.len(), 2);
+        assert!(failed.iter().any(|e| e.contains("invariant `total <= 3` failed")));
+        assert!(failed.iter().any(|e| e.contains("assert `total <= 4` failed")));
+
+        rte.put_current(clock, VarType::Int(1));
+        assert_eq!(rte.take_errors(), vec![]);
+    }
+}
@@ -1,7 +1,8 @@
-use crate::program::{Fragment, Operation, LacunaryRef, VarType};
-use std::collections::HashMap;
+use crate::ast::{self, Type};
+use crate::program::{self, Fragment, LacunaryRef, Operation, Signature, VarType};
+use std::collections::{BTreeMap, HashMap};
+use std::cell::RefCell;
 use std::rc::Rc;
-// use crate::ast::Type;
 
 // #[derive(Eq, PartialEq, Hash, Copy, Clone)]
 // pub struct FragmentRef {
@@ -9,18 +10,25 @@ use std::rc::Rc;
 //     pub index: usize,
 // }
 
-type Type = ();
-
 struct NodeScaffold {
     operation: Operation<LacunaryRef>,
-    node_type: Type
+    /// Statically known type of the node, if any.
+    node_type: Option<Type>,
 }
 
 pub struct FragmentBuilder<'a> {
     name: String,
     pub values_by_name: HashMap<String, LacunaryRef>,
+    pub input_types: Vec<Type>,
+    pub memoize: bool,
+    /// Values declared with `out`, by name.
+    pub outputs: BTreeMap<String, LacunaryRef>,
     values: Vec<NodeScaffold>,
     parent: Option<&'a FragmentBuilder<'a>>,
+    /// Warnings about the program, kept by the outermost builder.
+    warnings: RefCell<Vec<String>>,
+    /// Signatures of native functions, by the reference to their fragment.
+    signatures: HashMap<LacunaryRef, Signature>,
 }
 
 impl<'a> FragmentBuilder<'a> {
@@ -28,8 +36,13 @@ impl<'a> FragmentBuilder<'a> {
         FragmentBuilder {
             name,
             values_by_name: HashMap::new(),
+            input_types: Vec::new(),
+            memoize: true,
+            outputs: BTreeMap::new(),
             values: Vec::new(),
             parent: None,
+            warnings: RefCell::new(Vec::new()),
+            signatures: HashMap::new(),
         }
     }
 
@@ -49,34 +62,212 @@ impl<'a> FragmentBuilder<'a> {
         }
     }
 
-    pub fn alloc_value(&mut self, operation: Operation<LacunaryRef>) -> LacunaryRef {
+    pub fn parent(&self) -> Option<&'a FragmentBuilder<'a>> {
+        self.parent
+    }
+
+    /// The operation of a value allocated in this fragment.
+    pub fn operation(&self, index: usize) -> Option<&Operation<LacunaryRef>> {
+        self.values.get(index).map(|v| &v.operation)
+    }
+
+    /// The statically known type of the referenced value, if any.
+    pub fn type_of(&self, lr: &LacunaryRef) -> Option<Type> {
+        match lr {
+            LacunaryRef::ContextRef { up: 0, index } => {
+                self.values.get(*index).and_then(|v| v.node_type.clone())
+            }
+            LacunaryRef::InputRef { up: 0, index } => self.input_types.get(*index).cloned(),
+            LacunaryRef::ContextRef { up, index } => self
+                .parent
+                .and_then(|p| p.type_of(&LacunaryRef::ContextRef { up: up - 1, index: *index })),
+            LacunaryRef::InputRef { up, index } => self
+                .parent
+                .and_then(|p| p.type_of(&LacunaryRef::InputRef { up: up - 1, index: *index })),
+            LacunaryRef::InstanciatedRef(_) => None,
+        }
+    }
+
+    /// Work out the type of the result of an operation, checking that the
+    /// types of its operands are compatible where they are known.
+    fn infer_type(&self, operation: &Operation<LacunaryRef>) -> Result<Option<Type>, &'static str> {
+        use Operation::*;
 
-        self.values.push(NodeScaffold{ operation, node_type: ()});
+        let expect = |lr: &LacunaryRef, t: Type, err: &'static str| match self.type_of(lr) {
+            Some(found) if found != t => Err(err),
+            _ => Ok(()),
+        };
+
+        Ok(match operation {
+            External => None,
+            Const(v) => match v {
+                VarType::Int(_) => Some(Type::PrimInt),
+                VarType::Bool(_) => Some(Type::PrimBool),
+                VarType::Char(_) => Some(Type::PrimChar),
+                VarType::Str(_) => Some(Type::PrimString),
+                _ => None,
+            },
+            Vector(_) => None,
+            BinaryOp(a, b, op) => match op {
+                ast::BinaryOp::Sum => {
+                    expect(a, Type::PrimInt, "Operands of + must be integers.")?;
+                    expect(b, Type::PrimInt, "Operands of + must be integers.")?;
+                    Some(Type::PrimInt)
+                }
+                ast::BinaryOp::Eq
+                | ast::BinaryOp::Geq
+                | ast::BinaryOp::Leq
+                | ast::BinaryOp::Lt
+                | ast::BinaryOp::Gt => {
+                    if let (Some(ta), Some(tb)) = (self.type_of(a), self.type_of(b)) {
+                        if ta != tb {
+                            return Err("Cannot compare values of different types.");
+                        }
+                    }
+                    Some(Type::PrimBool)
+                }
+                ast::BinaryOp::Concat => match (self.type_of(a), self.type_of(b)) {
+                    (Some(ta), Some(tb)) if ta != tb => {
+                        return Err("Cannot concatenate values of different types.")
+                    }
+                    (ta, tb) => ta.or(tb),
+                },
+                ast::BinaryOp::Index => {
+                    expect(b, Type::PrimInt, "Index must be an integer.")?;
+                    match self.type_of(a) {
+                        Some(Type::PrimString) => Some(Type::PrimChar),
+                        Some(_) => return Err("Can only index into a string or vector."),
+                        None => None,
+                    }
+                }
+                ast::BinaryOp::Range => None,
+            },
+            ToString(_) => Some(Type::PrimString),
+            Sort(_) => None,
+            StringOp(op, _) => match op {
+                program::StringOp::Trim
+                | program::StringOp::Substr
+                | program::StringOp::Replace
+                | program::StringOp::ToUpper => Some(Type::PrimString),
+                program::StringOp::Find
+                | program::StringOp::Len
+                | program::StringOp::Clamp => Some(Type::PrimInt),
+                program::StringOp::Split | program::StringOp::ParseInt | program::StringOp::Get => None,
+            },
+            IfElse(g, b, e) => {
+                expect(g, Type::PrimBool, "Guard of if-else must be a boolean.")?;
+                match (self.type_of(b), self.type_of(e)) {
+                    (Some(tb), Some(te)) if tb != te => {
+                        return Err("Branches of if-else have different types.")
+                    }
+                    (tb, te) => tb.or(te),
+                }
+            }
+            ApplyFragment(f, args) => match self.signature(f) {
+                Some(signature) => self.check_arguments(signature, args)?,
+                None => None,
+            },
+            Native(f, args) => self.check_arguments(&f.signature, args)?,
+            Effect(kind, args) => self.check_arguments(&kind.signature(), args)?,
+            Check(_, claim, _, output) => {
+                expect(claim, Type::PrimBool, "Assertion must be a boolean.")?;
+                self.type_of(output)
+            }
+        })
+    }
 
-        LacunaryRef::ContextRef {
+    /// Check the arguments of a function against its signature, giving the
+    /// type of its result.
+    fn check_arguments(&self, signature: &Signature, args: &[LacunaryRef]) -> Result<Option<Type>, &'static str> {
+        if signature.inputs.len() != args.len() {
+            return Err("Wrong number of arguments.");
+        }
+
+        for (expected, arg) in signature.inputs.iter().zip(args) {
+            if let (Some(expected), Some(found)) = (expected, self.type_of(arg)) {
+                if *expected != found {
+                    return Err("Argument has the wrong type.");
+                }
+            }
+        }
+
+        Ok(signature.output.clone())
+    }
+
+    pub fn alloc_value(&mut self, operation: Operation<LacunaryRef>) -> Result<LacunaryRef, &'static str> {
+        let node_type = self.infer_type(&operation)?;
+
+        self.values.push(NodeScaffold{ operation, node_type });
+
+        Ok(LacunaryRef::ContextRef {
             up: 0,
             index: self.values.len() - 1,
-        }
+        })
     }
 
-    pub fn alloc_fragment(&mut self, frag: Fragment<LacunaryRef>) -> LacunaryRef {
+    pub fn alloc_fragment(&mut self, frag: Fragment<LacunaryRef>) -> Result<LacunaryRef, &'static str> {
         self.alloc_value(Operation::Const(VarType::Fragment(Rc::new(frag))))
     }
 
+    /// Declare the types of the arguments and result of the fragment `f`, so
+    /// that applications of it are type checked.
+    pub fn declare_signature(&mut self, f: LacunaryRef, signature: Signature) {
+        self.signatures.insert(f, signature);
+    }
+
+    fn signature(&self, f: &LacunaryRef) -> Option<&Signature> {
+        self.signatures
+            .get(f)
+            .or_else(|| self.parent.and_then(|p| p.signature(f)))
+    }
+
+    /// Report something suspicious about the program that doesn't prevent
+    /// building it.
+    pub fn warn(&self, warning: String) {
+        match self.parent {
+            Some(parent) => parent.warn(warning),
+            None => self.warnings.borrow_mut().push(warning),
+        }
+    }
+
+    pub fn take_warnings(&self) -> Vec<String> {
+        self.warnings.take()
+    }
+
     pub fn derive_child(&'a self, name: String) -> FragmentBuilder<'a> {
         FragmentBuilder {
             name,
             values_by_name: HashMap::new(),
+            input_types: Vec::new(),
+            memoize: true,
+            outputs: BTreeMap::new(),
             values: Vec::new(),
             parent: Some(self),
+            warnings: RefCell::new(Vec::new()),
+            signatures: HashMap::new(),
         }
     }
 
     pub fn build(self, output: LacunaryRef) -> Fragment<LacunaryRef> {
+        // Where several names refer to the same node, e.g. after `a = b`,
+        // use the first in alphabetical order so the choice is deterministic.
+        let mut names = BTreeMap::new();
+        for (name, lr) in self.values_by_name.iter() {
+            if let LacunaryRef::ContextRef { up: 0, index } = lr {
+                let entry = names.entry(*index).or_insert_with(|| name.clone());
+                if name < entry {
+                    *entry = name.clone();
+                }
+            }
+        }
+
         Fragment {
             name: self.name,
             nodes: self.values.into_iter().map(|v| v.operation).collect(),
             output,
+            memoize: self.memoize,
+            names,
+            outputs: self.outputs,
         }
     }
 }
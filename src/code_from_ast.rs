@@ -1,4 +1,5 @@
 use crate::ast::*;
+use itertools::Itertools;
 
 pub trait TempuraAST {
     fn gen_code(&self) -> String;
@@ -6,7 +7,10 @@ pub trait TempuraAST {
 
 impl TempuraAST for AssignmentAST {
     fn gen_code(&self) -> String {
-        format!("{} = {}", self.name.gen_code(), self.expr.gen_code())
+        match &self.valtype {
+            Some(t) => format!("{} : {} = {}", self.name.gen_code(), t.gen_code(), self.expr.gen_code()),
+            None => format!("{} = {}", self.name.gen_code(), self.expr.gen_code()),
+        }
     }
 }
 
@@ -15,28 +19,28 @@ impl TempuraAST for FragmentAST {
         let args = self
             .inputs
             .iter()
-            .map(|mi| {
-                format!(
-                    "{} : {}",
-                    mi.name.gen_code(),
-                    match mi.input_type {
-                        Type::PrimInt => "int",
-                        Type::PrimString => "str",
-                    }
-                )
-            })
+            .map(|mi| format!("{} : {}", mi.name.gen_code(), mi.input_type.gen_code()))
             .join(", ");
 
-        let submodules = self.submodules.iter().map(FragmentAST::gen_code).join("\n");
-
-        let valuedecls = self.assignments.iter().map(AssignmentAST::gen_code).join("\n");
-
-        let output = self.output.gen_code();
-
-        let declarations = format!("{}\n{}\n{}", submodules, valuedecls, output);
+        let declarations = self
+            .submodules
+            .iter()
+            .map(FragmentAST::gen_code)
+            .chain(self.assignments.iter().map(|a| {
+                if self.outputs.contains(&a.name) {
+                    format!("out {}", a.gen_code())
+                } else {
+                    a.gen_code()
+                }
+            }))
+            .chain(self.assertions.iter().map(|a| format!("{} {}", a.kind.keyword(), a.claim.gen_code())))
+            .chain(std::iter::once(self.output.gen_code()))
+            .flat_map(|decl| decl.lines().map(|l| format!("    {}", l)).collect::<Vec<_>>())
+            .join("\n");
 
         format!(
-            "mod {modname}({args}) {{ \n {declarations} \n }}",
+            "{nomemo}mod {modname}({args}) {{\n{declarations}\n}}",
+            nomemo = if self.memoize { "" } else { "nomemo " },
             modname = self.name.gen_code(),
             args = args,
             declarations = declarations
@@ -44,18 +48,86 @@ impl TempuraAST for FragmentAST {
     }
 }
 
+impl TempuraAST for Type {
+    fn gen_code(&self) -> String {
+        match self {
+            Type::PrimInt => "int",
+            Type::PrimString => "str",
+            Type::PrimBool => "bool",
+            Type::PrimChar => "char",
+        }
+        .to_string()
+    }
+}
+
+impl TempuraAST for BinaryOp {
+    fn gen_code(&self) -> String {
+        match self {
+            BinaryOp::Sum => "+",
+            BinaryOp::Geq => ">=",
+            BinaryOp::Leq => "<=",
+            BinaryOp::Eq => "==",
+            BinaryOp::Lt => "<",
+            BinaryOp::Gt => ">",
+            BinaryOp::Concat => "<>",
+            BinaryOp::Range => "..",
+            BinaryOp::Index => unreachable!("indexing is not an infix operator"),
+        }
+        .to_string()
+    }
+}
+
+/// Escape text so that it reads back as the same string literal contents.
+fn escape_string(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '"' => "\\\"".to_string(),
+            '\\' => "\\\\".to_string(),
+            '$' => "\\$".to_string(),
+            '\n' => "\\n".to_string(),
+            '\r' => "\\r".to_string(),
+            '\t' => "\\t".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Generate code for an operand position that only accepts a single expression,
+/// adding parentheses where needed.
+fn gen_operand(e: &Expression) -> String {
+    match e {
+        Expression::ConstString(_)
+        | Expression::Interpolation(_)
+        | Expression::ConstInteger(_)
+        | Expression::ConstBoolean(_)
+        | Expression::LacunaryRef(_) => e.gen_code(),
+        _ => format!("({})", e.gen_code()),
+    }
+}
+
 impl TempuraAST for Expression {
     fn gen_code(&self) -> String {
         match self {
-            Expression::ConstString(s) => format!("\"{}\"", s),
+            Expression::ConstString(s) => format!("\"{}\"", escape_string(s)),
+            Expression::Interpolation(parts) => format!(
+                "\"{}\"",
+                parts
+                    .iter()
+                    .map(|part| match part {
+                        StringPart::Literal(s) => escape_string(s),
+                        StringPart::Interpolated(e) => format!("${{{}}}", e.gen_code()),
+                    })
+                    .join("")
+            ),
             Expression::ConstInteger(i) => i.to_string(),
             Expression::ConstBoolean(b) => (if *b { "true" } else { "false" }).to_string(),
             Expression::LacunaryRef(n) => n.gen_code(),
-            Expression::ContainerIndexing(c, i) => {
-                format!("\"{}\"[{}]", c.gen_code(), i.gen_code())
+            Expression::BinaryOp(c, i, BinaryOp::Index) => {
+                format!("{}[{}]", gen_operand(c), i.gen_code())
+            }
+            Expression::BinaryOp(a, b, op) => {
+                format!("{} {} {}", gen_operand(a), op.gen_code(), gen_operand(b))
             }
-            Expression::Range { from, to } => format!("{}..{}", from.gen_code(), to.gen_code()),
-            Expression::Sum { a, b } => format!("{}..{}", a.gen_code(), b.gen_code()),
             Expression::ModuleApplication {
                 mod_name,
                 arguments,
@@ -68,11 +140,28 @@ impl TempuraAST for Expression {
                 guard,
                 body,
                 else_body,
-            } => format!(
-                "if {} then {} else {}",
-                guard.gen_code(),
-                body.gen_code(),
-                else_body.gen_code()
+            } => {
+                // Nested conditionals in the else branch are printed as an elif chain.
+                let mut code = format!("if {} then {}", guard.gen_code(), body.gen_code());
+                let mut rest = else_body;
+
+                while let Expression::IfElse {
+                    guard,
+                    body,
+                    else_body,
+                } = rest.as_ref()
+                {
+                    code.push_str(&format!(" elif {} then {}", guard.gen_code(), body.gen_code()));
+                    rest = else_body;
+                }
+
+                code.push_str(&format!(" else {}", rest.gen_code()));
+                code
+            }
+            Expression::Let { bindings, body } => format!(
+                "let {} in {}",
+                bindings.iter().map(AssignmentAST::gen_code).join("; "),
+                body.gen_code()
             ),
         }
     }
@@ -82,4 +171,52 @@ impl TempuraAST for Name {
     fn gen_code(&self) -> String {
         self.0.clone()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nom_parse::*;
+    use nom::error::VerboseError;
+
+    fn roundtrip(src: &str) -> String {
+        expression::<VerboseError<&str>>(src).unwrap().1.gen_code()
+    }
+
+    #[test]
+    fn test_elif_chain_roundtrip() {
+        let chain = "if a == 1 then \"one\" elif a == 2 then \"two\" else \"many\"";
+
+        assert_eq!(roundtrip(chain), chain);
+        assert_eq!(
+            roundtrip("cond { a == 1 => \"one\", a == 2 => \"two\", else => \"many\" }"),
+            chain
+        );
+    }
+
+    #[test]
+    fn test_comparison_roundtrip() {
+        for src in &["a < b", "a > b", "a <= b", "a >= b"] {
+            assert_eq!(roundtrip(src), *src);
+        }
+    }
+
+    #[test]
+    fn test_module_roundtrip() {
+        let src = r#"mod main(x : int) {
+            nomemo mod fb(i : int) {
+                "i: ${i + 1} \$"
+            }
+            y = let a = x + 1 in a + a
+            out c = "abc"[y]
+            assert y >= 0
+            invariant y < 100
+            fb(y)
+        }"#;
+
+        let parsed = module::<VerboseError<&str>>(src).unwrap().1;
+        let code = parsed.gen_code();
+
+        assert_eq!(module::<VerboseError<&str>>(&code).unwrap().1, parsed);
+    }
+}
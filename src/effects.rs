@@ -0,0 +1,616 @@
+//! Effects programs ask the host to perform, such as reading a file, and the
+//! handlers that perform them.
+//!
+//! Applying an effect like `read_file(path)` makes the runtime queue a request,
+//! which the host takes with `RuntimeEnv::take_requests` and starts with an
+//! `EffectHandler`. The value of the application is null, or false for
+//! timers, until the host delivers the result with `RuntimeEnv::complete`,
+//! which puts it on the node like a value put on an input. When the arguments
+//! change, a new request is made, and the old one is cancelled. Builtins and
+//! operators applied to a null value, like that of a pending effect, give null.
+//!
+//! Watches like `file_contents(path)` or `every(250ms)` deliver a result
+//! whenever what they look at changes, until they are cancelled.
+
+use crate::program::{EffectKind, VarType};
+use crate::run::{RuntimeEnv, Time};
+use std::collections::HashMap;
+use std::fs;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub type EffectId = u64;
+
+/// How often `SystemEffects` checks watched files and directories for changes.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EffectRequest {
+    pub id: EffectId,
+    pub kind: EffectKind,
+    pub args: Vec<VarType>,
+}
+
+/// The result of performing a request, or why it failed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EffectResult {
+    pub id: EffectId,
+    pub value: Result<EffectValue, String>,
+}
+
+/// A value produced by an effect, which unlike `VarType` can be sent
+/// between threads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EffectValue {
+    Str(String),
+    Bool(bool),
+    Int(i64),
+    List(Vec<EffectValue>),
+}
+
+impl From<&str> for EffectValue {
+    fn from(s: &str) -> EffectValue {
+        EffectValue::Str(s.to_string())
+    }
+}
+
+impl From<EffectValue> for VarType {
+    fn from(v: EffectValue) -> VarType {
+        match v {
+            EffectValue::Str(s) => VarType::from(s),
+            EffectValue::Bool(b) => VarType::Bool(b),
+            EffectValue::Int(i) => VarType::Int(i),
+            EffectValue::List(l) => VarType::from(l.into_iter().map(VarType::from).collect::<Vec<_>>()),
+        }
+    }
+}
+
+/// Performs effects for a runtime.
+pub trait EffectHandler {
+    /// Start performing an effect without waiting for it, and call `done`
+    /// with the result once it is known, possibly from another thread.
+    /// Watches call `done` again whenever the result changes.
+    fn start(&mut self, request: EffectRequest, done: Box<dyn Fn(EffectResult) + Send>);
+
+    /// Stop performing an effect whose results are no longer wanted.
+    fn cancel(&mut self, _id: EffectId) {}
+}
+
+/// Cancel the effects the runtime no longer wants, and start the ones it
+/// requested since the last call, calling `deliver` with each result.
+pub fn start_requested(
+    rte: &mut RuntimeEnv,
+    handler: &mut dyn EffectHandler,
+    deliver: impl Fn(EffectResult) + Clone + Send + 'static,
+) {
+    for id in rte.take_cancelled() {
+        handler.cancel(id);
+    }
+
+    for request in rte.take_requests() {
+        let deliver = deliver.clone();
+        handler.start(request, Box::new(move |result| deliver(result)));
+    }
+}
+
+/// Performs effects on the system, each on a thread of its own. Watches of
+/// files and directories poll every `POLL_INTERVAL`.
+#[derive(Default)]
+pub struct SystemEffects {
+    /// Whether each running watch should keep going.
+    watches: HashMap<EffectId, Arc<AtomicBool>>,
+}
+
+impl SystemEffects {
+    pub fn new() -> SystemEffects {
+        SystemEffects::default()
+    }
+}
+
+impl EffectHandler for SystemEffects {
+    fn start(&mut self, request: EffectRequest, done: Box<dyn Fn(EffectResult) + Send>) {
+        let id = request.id;
+        let job = match (request.kind, &request.args[..]) {
+            (EffectKind::ReadFile, [VarType::Str(path)])
+            | (EffectKind::FileContents, [VarType::Str(path)]) => Job::ReadFile(path.to_string()),
+            (EffectKind::DirListing, [VarType::Str(path)]) => Job::ListDir(path.to_string()),
+            (EffectKind::Run, [VarType::Str(command)]) => Job::Run(command.to_string()),
+            (EffectKind::Timer, [VarType::Int(ms)]) => Job::Timer((*ms).max(0) as u64),
+            (EffectKind::Every, [VarType::Int(ms)]) => Job::Timer((*ms).max(1) as u64),
+            (kind, _) => {
+                let value = Err(format!("wrong arguments for {}", kind.name()));
+                return done(EffectResult { id, value });
+            }
+        };
+
+        if !request.kind.is_watch() {
+            thread::spawn(move || done(EffectResult { id, value: job.perform() }));
+            return;
+        }
+
+        let active = Arc::new(AtomicBool::new(true));
+        self.watches.insert(id, active.clone());
+
+        if let (EffectKind::Every, Job::Timer(ms)) = (request.kind, &job) {
+            let period = Duration::from_millis(*ms);
+            thread::spawn(move || {
+                // Schedule ticks from the start, so that they don't drift.
+                let start = Instant::now();
+                let mut ticks = 0;
+                loop {
+                    ticks += 1;
+                    thread::sleep((start + period * ticks).saturating_duration_since(Instant::now()));
+                    if !active.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    done(EffectResult {
+                        id,
+                        value: Ok(EffectValue::Int(ticks as i64)),
+                    });
+                }
+            });
+            return;
+        }
+
+        thread::spawn(move || {
+            let mut last = None;
+            while active.load(Ordering::Relaxed) {
+                let value = job.perform();
+                if last.as_ref() != Some(&value) {
+                    done(EffectResult { id, value: value.clone() });
+                    last = Some(value);
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        });
+    }
+
+    fn cancel(&mut self, id: EffectId) {
+        if let Some(active) = self.watches.remove(&id) {
+            active.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// An effect with its arguments taken out of the runtime's values, so that it
+/// can be performed on another thread.
+enum Job {
+    ReadFile(String),
+    ListDir(String),
+    Run(String),
+    Timer(u64),
+}
+
+impl Job {
+    fn perform(&self) -> Result<EffectValue, String> {
+        match self {
+            Job::ReadFile(path) => fs::read_to_string(path)
+                .map(EffectValue::Str)
+                .map_err(|e| format!("{}: {}", path, e)),
+            Job::ListDir(path) => {
+                let mut names = fs::read_dir(path)
+                    .and_then(|entries| {
+                        entries
+                            .map(|e| e.map(|e| e.file_name().to_string_lossy().into_owned()))
+                            .collect::<Result<Vec<String>, _>>()
+                    })
+                    .map_err(|e| format!("{}: {}", path, e))?;
+                names.sort();
+                Ok(EffectValue::List(names.into_iter().map(EffectValue::Str).collect()))
+            }
+            Job::Run(command) => {
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .output()
+                    .map_err(|e| e.to_string())?;
+
+                if output.status.success() {
+                    Ok(EffectValue::Str(String::from_utf8_lossy(&output.stdout).into_owned()))
+                } else {
+                    Err(format!("`{}` {}", command, output.status))
+                }
+            }
+            Job::Timer(ms) => {
+                thread::sleep(Duration::from_millis(*ms));
+                Ok(EffectValue::Bool(true))
+            }
+        }
+    }
+}
+
+/// Performs effects with results given in advance, only when told to, so
+/// that tests control exactly when results arrive. Timers run in virtual
+/// time, which only passes with `advance`.
+#[derive(Default)]
+pub struct FakeEffects {
+    results: HashMap<(EffectKind, Vec<VarType>), Result<EffectValue, String>>,
+    started: Vec<(EffectRequest, Box<dyn Fn(EffectResult) + Send>)>,
+    /// Virtual time in milliseconds.
+    now: u64,
+    timers: Vec<FakeTimer>,
+}
+
+struct FakeTimer {
+    request: EffectRequest,
+    done: Box<dyn Fn(EffectResult) + Send>,
+    /// Virtual time of the next tick.
+    due: u64,
+    period: u64,
+    ticks: i64,
+}
+
+impl FakeEffects {
+    pub fn new() -> FakeEffects {
+        FakeEffects::default()
+    }
+
+    /// Give `value` as the result of the effect with these arguments.
+    pub fn set_result(&mut self, kind: EffectKind, args: Vec<VarType>, value: Result<EffectValue, String>) {
+        self.results.insert((kind, args), value);
+    }
+
+    /// The requests started and not finished or cancelled yet, oldest first,
+    /// followed by the running timers.
+    pub fn pending(&self) -> Vec<&EffectRequest> {
+        self.started
+            .iter()
+            .map(|(r, _)| r)
+            .chain(self.timers.iter().map(|t| &t.request))
+            .collect()
+    }
+
+    /// Deliver the results of the started requests other than timers in the
+    /// order they were started. Requests without a result fail. Watches stay
+    /// started, and deliver their current result again every time.
+    pub fn finish_all(&mut self) {
+        for (request, done) in self.started.iter() {
+            let value = self
+                .results
+                .get(&(request.kind, request.args.clone()))
+                .cloned()
+                .unwrap_or_else(|| Err("no result given".to_string()));
+
+            done(EffectResult {
+                id: request.id,
+                value,
+            });
+        }
+
+        self.started.retain(|(request, _)| request.kind.is_watch());
+    }
+
+    /// Virtual milliseconds passed so far.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Let `ms` milliseconds of virtual time pass, delivering the ticks of
+    /// timers in the order they are due. Timers due at the same time tick in
+    /// the order they were started.
+    pub fn advance(&mut self, ms: u64) {
+        let until = self.now + ms;
+
+        while let Some(i) = (0..self.timers.len())
+            .filter(|i| self.timers[*i].due <= until)
+            .min_by_key(|i| self.timers[*i].due)
+        {
+            let timer = &mut self.timers[i];
+            self.now = timer.due;
+            timer.ticks += 1;
+
+            let value = match timer.request.kind {
+                EffectKind::Every => EffectValue::Int(timer.ticks),
+                _ => EffectValue::Bool(true),
+            };
+            (timer.done)(EffectResult {
+                id: timer.request.id,
+                value: Ok(value),
+            });
+
+            if timer.request.kind == EffectKind::Every {
+                timer.due += timer.period;
+            } else {
+                self.timers.remove(i);
+            }
+        }
+
+        self.now = until;
+    }
+
+    /// Put the virtual time on the `millis` and `wallclock` inputs of the
+    /// runtime, as the CLI does with the real time. The virtual clock starts
+    /// at the Unix epoch.
+    pub fn put_time(&self, rte: &mut RuntimeEnv) -> Time {
+        let now = VarType::Int(self.now as i64);
+        let (millis, wallclock) = (rte.input("millis").unwrap(), rte.input("wallclock").unwrap());
+        rte.begin().set(millis, now.clone()).set(wallclock, now).commit()
+    }
+}
+
+impl EffectHandler for FakeEffects {
+    fn start(&mut self, request: EffectRequest, done: Box<dyn Fn(EffectResult) + Send>) {
+        match (request.kind, &request.args[..]) {
+            (EffectKind::Timer, [VarType::Int(ms)]) | (EffectKind::Every, [VarType::Int(ms)]) => {
+                let period = if request.kind == EffectKind::Every {
+                    (*ms).max(1) as u64
+                } else {
+                    (*ms).max(0) as u64
+                };
+
+                self.timers.push(FakeTimer {
+                    request,
+                    done,
+                    due: self.now + period,
+                    period,
+                    ticks: 0,
+                });
+            }
+            _ => self.started.push((request, done)),
+        }
+    }
+
+    fn cancel(&mut self, id: EffectId) {
+        self.started.retain(|(request, _)| request.id != id);
+        self.timers.retain(|timer| timer.request.id != id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::build_runtime;
+    use crate::nom_parse::parse_tempura;
+    use crate::run::RuntimeError;
+    use nom::error::VerboseError;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_effects() {
+        let src = r#"mod main() {
+            path = if clock >= 2 then "b.txt" else "a.txt"
+            read_file(path)
+        }"#;
+
+        let modu = parse_tempura::<VerboseError<&str>>(src).unwrap().1;
+        let mut rte = build_runtime(modu).unwrap();
+        let stdout = rte.output("stdout").unwrap();
+        let clock = rte.input("clock").unwrap();
+
+        let mut fake = FakeEffects::new();
+        fake.set_result(EffectKind::ReadFile, vec!["a.txt".into()], Ok("apple".into()));
+        fake.set_result(EffectKind::ReadFile, vec!["b.txt".into()], Err("no such file".into()));
+
+        let (tx, rx) = mpsc::channel();
+        let deliver = move |result| tx.send(result).unwrap();
+
+        // The value is null until the result is delivered.
+        assert_eq!(rte.pull_once(stdout), VarType::Null);
+        start_requested(&mut rte, &mut fake, deliver.clone());
+        assert_eq!(fake.pending().len(), 1);
+        fake.finish_all();
+        let time = rte.complete(rx.recv().unwrap());
+        assert_eq!(time, Some(rte.current_time()));
+        assert_eq!(rte.pull_once(stdout), VarType::from("apple"));
+
+        // Ticks that don't change the path don't read the file again.
+        rte.put_current(clock, VarType::Int(1));
+        start_requested(&mut rte, &mut fake, deliver.clone());
+        assert!(fake.pending().is_empty());
+
+        // Results of requests made stale by a new one are ignored.
+        rte.put_current(clock, VarType::Int(2));
+        start_requested(&mut rte, &mut fake, deliver.clone());
+        let stale = EffectResult {
+            id: fake.pending()[0].id - 1,
+            value: Ok("old".into()),
+        };
+        assert_eq!(rte.complete(stale), None);
+
+        // Failures are reported as errors, and put the pending value back.
+        fake.finish_all();
+        let time = rte.complete(rx.recv().unwrap()).unwrap();
+        assert_eq!(rte.pull_once(stdout), VarType::Null);
+        let errors = rte.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(&errors[0], RuntimeError::Effect { effect, time: t, .. } if effect == "read_file" && *t == time));
+        assert!(errors[0].to_string().ends_with(": no such file"));
+
+        // Requests that weren't taken yet are dropped rather than cancelled.
+        rte.put_current(clock, VarType::Int(0));
+        start_requested(&mut rte, &mut fake, deliver.clone());
+        let running = fake.pending()[0].id;
+        rte.put_current(clock, VarType::Int(2));
+        rte.cancel_effects();
+        assert!(rte.take_requests().is_empty());
+        assert_eq!(rte.take_cancelled(), vec![running]);
+    }
+
+    #[test]
+    fn test_pending_values_propagate() {
+        let src = r#"mod main() {
+            out trimmed = trim(read_file("a.txt"))
+            out size = len(file_contents("b.txt"))
+            out lines = sort(split(read_file("a.txt"), "\n"))
+            if (file_contents("b.txt")) == "" then "empty" else "full"
+        }"#;
+
+        let modu = parse_tempura::<VerboseError<&str>>(src).unwrap().1;
+        let mut rte = build_runtime(modu).unwrap();
+        let outputs: Vec<_> = ["trimmed", "size", "lines"].iter().map(|o| rte.output(o).unwrap()).collect();
+
+        for output in outputs.iter() {
+            assert_eq!(rte.pull_once(*output), VarType::Null);
+        }
+
+        let mut fake = FakeEffects::new();
+        fake.set_result(EffectKind::ReadFile, vec!["a.txt".into()], Ok(" b\na ".into()));
+        fake.set_result(EffectKind::FileContents, vec!["b.txt".into()], Ok("four".into()));
+        let (tx, rx) = mpsc::channel();
+        start_requested(&mut rte, &mut fake, move |result| tx.send(result).unwrap());
+        fake.finish_all();
+        for result in rx.try_iter() {
+            rte.complete(result);
+        }
+
+        let values: Vec<VarType> = outputs.iter().map(|o| rte.pull_once(*o)).collect();
+        let lines = VarType::from(vec![" b", "a "]);
+        assert_eq!(values, vec![VarType::from("b\na"), VarType::Int(4), lines]);
+        assert_eq!(rte.pull_once(rte.output("stdout").unwrap()), VarType::from("full"));
+    }
+
+    #[test]
+    fn test_effects_type_checked() {
+        let src = r#"mod main() {
+            after("soon")
+        }"#;
+
+        let modu = parse_tempura::<VerboseError<&str>>(src).unwrap().1;
+        assert_eq!(build_runtime(modu).err(), Some("Argument has the wrong type."));
+    }
+
+    #[test]
+    fn test_watches() {
+        let src = r#"mod main(dir : str) {
+            out files = dir_listing(dir)
+            file_contents("$dir/config")
+        }"#;
+
+        let mut rte = crate::build::build_runtime_with(
+            parse_tempura::<VerboseError<&str>>(src).unwrap().1,
+            &[],
+            &[],
+        )
+        .unwrap();
+        let stdout = rte.output("stdout").unwrap();
+        let files = rte.output("files").unwrap();
+        rte.put_current(rte.input("dir").unwrap(), VarType::from("etc"));
+
+        let mut fake = FakeEffects::new();
+        let config = (EffectKind::FileContents, vec![VarType::from("etc/config")]);
+        fake.set_result(config.0, config.1.clone(), Ok("a = 1".into()));
+        fake.set_result(
+            EffectKind::DirListing,
+            vec![VarType::from("etc")],
+            Ok(EffectValue::List(vec!["config".into()])),
+        );
+
+        let (tx, rx) = mpsc::channel();
+        let mut run = |rte: &mut RuntimeEnv, fake: &mut FakeEffects| {
+            rte.pull_once(stdout);
+            rte.pull_once(files);
+            let tx = tx.clone();
+            start_requested(rte, fake, move |result| tx.send(result).unwrap());
+            fake.finish_all();
+            for result in rx.try_iter() {
+                rte.complete(result);
+            }
+        };
+
+        run(&mut rte, &mut fake);
+        assert_eq!(rte.pull_once(stdout), VarType::from("a = 1"));
+        assert_eq!(rte.pull_once(files), VarType::from(vec![VarType::from("config")]));
+
+        // Watches keep delivering as the file changes.
+        fake.set_result(config.0, config.1.clone(), Ok("a = 2".into()));
+        run(&mut rte, &mut fake);
+        assert_eq!(rte.pull_once(stdout), VarType::from("a = 2"));
+        assert_eq!(fake.pending().len(), 2);
+
+        // A file that disappears gives null and an error.
+        fake.set_result(config.0, config.1.clone(), Err("etc/config: not found".into()));
+        run(&mut rte, &mut fake);
+        assert_eq!(rte.pull_once(stdout), VarType::Null);
+        assert_eq!(rte.take_errors().len(), 1);
+
+        // Watching another directory cancels the old watches.
+        rte.put_current(rte.input("dir").unwrap(), VarType::from("opt"));
+        run(&mut rte, &mut fake);
+        let mut watched: Vec<&VarType> = fake.pending().iter().map(|r| &r.args[0]).collect();
+        watched.sort();
+        assert_eq!(watched, vec![&VarType::from("opt"), &VarType::from("opt/config")]);
+    }
+
+    #[test]
+    fn test_list_dir() {
+        let dir = std::env::temp_dir().join(format!("tempura-list-dir-{}", std::process::id()));
+        fs::create_dir_all(dir.join("b")).unwrap();
+        fs::write(dir.join("a.txt"), "").unwrap();
+
+        let job = Job::ListDir(dir.to_string_lossy().into_owned());
+        assert_eq!(job.perform(), Ok(EffectValue::List(vec!["a.txt".into(), "b".into()])));
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(job.perform().is_err());
+    }
+
+    #[test]
+    fn test_virtual_time() {
+        let src = r#"mod main() {
+            fast = every(250ms)
+            slow = every(1s)
+            done = after(600ms)
+            "$fast $slow $done"
+        }"#;
+
+        let modu = parse_tempura::<VerboseError<&str>>(src).unwrap().1;
+        let mut rte = build_runtime(modu).unwrap();
+        let stdout = rte.output("stdout").unwrap();
+
+        let seen = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let seen_cb = seen.clone();
+        rte.listen(
+            stdout,
+            true,
+            crate::run::ListenMode::OnChange,
+            Box::new(move |_, v| seen_cb.borrow_mut().push(v.render_as_string())),
+        )
+        .detach();
+
+        let mut fake = FakeEffects::new();
+        let (tx, rx) = mpsc::channel();
+        start_requested(&mut rte, &mut fake, move |result| tx.send(result).unwrap());
+        assert_eq!(fake.pending().len(), 3);
+
+        fake.advance(1000);
+        assert_eq!(fake.now(), 1000);
+        for result in rx.try_iter() {
+            rte.complete(result);
+        }
+
+        assert_eq!(
+            *seen.borrow(),
+            vec!["0 0 false", "1 0 false", "2 0 false", "2 0 true", "3 0 true", "4 0 true", "4 1 true"]
+        );
+
+        // The timer is done, the clocks keep running.
+        assert_eq!(fake.pending().len(), 2);
+    }
+
+    #[test]
+    fn test_virtual_clock() {
+        let src = r#"mod main() {
+            out later = millis + 100
+            wallclock
+        }"#;
+
+        let modu = parse_tempura::<VerboseError<&str>>(src).unwrap().1;
+        let mut rte = build_runtime(modu).unwrap();
+        let (stdout, later) = (rte.output("stdout").unwrap(), rte.output("later").unwrap());
+
+        // Until the host puts the time on them, the inputs are null.
+        assert_eq!(rte.pull_once(later), VarType::Null);
+
+        let mut fake = FakeEffects::new();
+        fake.put_time(&mut rte);
+        assert_eq!(rte.pull_once(later), VarType::Int(100));
+
+        fake.advance(1500);
+        let time = fake.put_time(&mut rte);
+        assert_eq!(time, rte.current_time());
+        assert_eq!((rte.pull_once(later), rte.pull_once(stdout)), (VarType::Int(1600), VarType::Int(1500)));
+    }
+}
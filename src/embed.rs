@@ -0,0 +1,472 @@
+//! The API for running tempura programs from Rust: compile a program once
+//! with `Program::compile`, then create instances of it to put values on
+//! their inputs and read or subscribe to their outputs.
+
+use crate::ast::FragmentAST;
+use crate::build::build_runtime_with;
+use crate::effects::{EffectRequest, EffectResult};
+use crate::nom_parse::parse_tempura;
+use crate::program::{NativeFunction, NodeIndex, VarType};
+use crate::reload::{reload, ReloadReport};
+use crate::run::{ListenMode, ListenerHandle, RuntimeEnv, RuntimeError, Time};
+use nom::error::VerboseError;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The source couldn't be parsed.
+    Parse(String),
+    /// The program couldn't be built, for instance because of a type error.
+    Build(&'static str),
+    UnknownInput(String),
+    UnknownOutput(String),
+    /// A value didn't have the type it was converted to, or the type of the
+    /// input it was put on.
+    WrongType { name: String, value: VarType },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(e) => write!(f, "{}", e),
+            Error::Build(e) => write!(f, "build failed: {}", e),
+            Error::UnknownInput(name) => write!(f, "no input named {}", name),
+            Error::UnknownOutput(name) => write!(f, "no output named {}", name),
+            Error::WrongType { name, value } => write!(f, "{} has the wrong type: {}", name, value.describe()),
+        }
+    }
+}
+
+/// A program that was checked to build, from which any number of independent
+/// instances can be created.
+#[derive(Clone, Debug)]
+pub struct Program {
+    ast: FragmentAST,
+    inputs: Vec<String>,
+    natives: Vec<Rc<NativeFunction>>,
+    /// Warnings about the program found while building it.
+    pub warnings: Vec<String>,
+}
+
+impl Program {
+    /// Compile a program that can refer to external inputs with the given
+    /// names, besides `stdin`, `clock` and the parameters of its main module.
+    pub fn compile(source: &str, inputs: &[&str]) -> Result<Program, Error> {
+        Program::compile_with_natives(source, inputs, vec![])
+    }
+
+    /// Compile a program that can also call the given native functions.
+    pub fn compile_with_natives(
+        source: &str,
+        inputs: &[&str],
+        natives: Vec<NativeFunction>,
+    ) -> Result<Program, Error> {
+        let ast = match parse_tempura::<VerboseError<&str>>(source) {
+            Ok((_, ast)) => ast,
+            Err(e) => return Err(Error::Parse(e.to_string())),
+        };
+
+        let natives: Vec<Rc<NativeFunction>> = natives.into_iter().map(Rc::new).collect();
+        let rte = build_runtime_with(ast.clone(), inputs, &natives).map_err(Error::Build)?;
+
+        Ok(Program {
+            ast,
+            inputs: inputs.iter().map(|i| i.to_string()).collect(),
+            natives,
+            warnings: rte.warnings,
+        })
+    }
+
+    pub fn instantiate(&self) -> Result<Instance, Error> {
+        let inputs: Vec<&str> = self.inputs.iter().map(|i| &i[..]).collect();
+        let rte = build_runtime_with(self.ast.clone(), &inputs, &self.natives).map_err(Error::Build)?;
+
+        Ok(Instance {
+            rte,
+            main: self.ast.name.0.clone(),
+        })
+    }
+}
+
+/// A running instance of a program.
+///
+/// Outputs are the values the main module declares with `out`, and its other
+/// named values.
+pub struct Instance {
+    rte: RuntimeEnv,
+    /// Name of the main module, which prefixes the stable ids of its values.
+    main: String,
+}
+
+impl Instance {
+    fn input(&self, name: &str) -> Result<NodeIndex, Error> {
+        self.rte
+            .input(name)
+            .ok_or_else(|| Error::UnknownInput(name.to_string()))
+    }
+
+    fn output(&self, name: &str) -> Result<NodeIndex, Error> {
+        self.rte
+            .output(name)
+            .or_else(|| self.rte.find_node(&format!("{}/{}", self.main, name)))
+            .ok_or_else(|| Error::UnknownOutput(name.to_string()))
+    }
+
+    fn convert<T: TryFrom<VarType, Error = VarType>>(name: &str, value: VarType) -> Result<T, Error> {
+        T::try_from(value).map_err(|value| Error::WrongType {
+            name: name.to_string(),
+            value,
+        })
+    }
+
+    /// An input that the value can be put on.
+    fn typed_input(&self, name: &str, value: &VarType) -> Result<NodeIndex, Error> {
+        let idx = self.input(name)?;
+        match self.rte.input_type(idx) {
+            Some(t) if !value.has_type(t) => Err(Error::WrongType {
+                name: name.to_string(),
+                value: value.clone(),
+            }),
+            _ => Ok(idx),
+        }
+    }
+
+    /// Put a value on an input, and propagate it.
+    pub fn set(&mut self, input: &str, value: impl Into<VarType>) -> Result<Time, Error> {
+        let value = value.into();
+        let idx = self.typed_input(input, &value)?;
+        Ok(self.rte.put_current(idx, value))
+    }
+
+    /// Put values on several inputs at the same time. An input given more
+    /// than once takes each of its values in turn, at successive times.
+    pub fn set_all(&mut self, values: Vec<(&str, VarType)>) -> Result<Time, Error> {
+        let values = values
+            .into_iter()
+            .map(|(input, value)| Ok((self.typed_input(input, &value)?, value)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut transaction = self.rte.begin();
+        for (idx, value) in values {
+            transaction = transaction.append(idx, value);
+        }
+        Ok(transaction.commit())
+    }
+
+    /// The current value of a named value of the main module.
+    pub fn get<T: TryFrom<VarType, Error = VarType>>(&mut self, name: &str) -> Result<T, Error> {
+        let idx = self.output(name)?;
+        let value = self.rte.pull_once(idx);
+        Self::convert(name, value)
+    }
+
+    /// The current result of the main module.
+    pub fn result<T: TryFrom<VarType, Error = VarType>>(&mut self) -> Result<T, Error> {
+        self.get("stdout")
+    }
+
+    /// Call `callback` with the value of a named value of the main module
+    /// now, and whenever it changes.
+    pub fn subscribe(
+        &mut self,
+        name: &str,
+        callback: Box<dyn FnMut(Time, &VarType)>,
+    ) -> Result<ListenerHandle, Error> {
+        let idx = self.output(name)?;
+        Ok(self.rte.listen(idx, true, ListenMode::OnChange, callback))
+    }
+
+    /// Call `callback` with the result of the main module now, and whenever
+    /// it changes.
+    pub fn subscribe_result(&mut self, callback: Box<dyn FnMut(Time, &VarType)>) -> ListenerHandle {
+        let idx = self.rte.output("stdout").unwrap();
+        self.rte.listen(idx, true, ListenMode::OnChange, callback)
+    }
+
+    /// Call `callback` with the result of the main module now, and every time
+    /// it is recomputed, even to the value it already had. For results that
+    /// stream characters, where the same character twice is new output.
+    pub fn stream_result(&mut self, callback: Box<dyn FnMut(Time, &VarType)>) -> ListenerHandle {
+        let idx = self.rte.output("stdout").unwrap();
+        self.rte.set_suppress_unchanged(idx, false);
+        self.rte.listen(idx, true, ListenMode::EveryUpdate, callback)
+    }
+
+    /// Names of the values the main module declares with `out`.
+    pub fn outputs(&self) -> Vec<String> {
+        self.rte
+            .outputs()
+            .filter(|(name, _)| *name != "stdout")
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+
+    /// Switch to another version of the program, keeping the state of
+    /// everything whose definition didn't change.
+    pub fn reload(&mut self, program: &Program) -> Result<ReloadReport, Error> {
+        let new = program.instantiate()?;
+        let old = std::mem::replace(&mut self.rte, RuntimeEnv::new());
+        let (rte, report) = reload(old, new.rte);
+        self.rte = rte;
+        self.main = new.main;
+        Ok(report)
+    }
+
+    /// Errors such as failed runtime checks since the last call.
+    pub fn take_errors(&mut self) -> Vec<RuntimeError> {
+        self.rte.take_errors()
+    }
+
+    /// Effects the program asked for since the last call, to be started with
+    /// an `EffectHandler`.
+    pub fn take_requests(&mut self) -> Vec<EffectRequest> {
+        self.rte.take_requests()
+    }
+
+    /// Deliver the result of an effect. Returns the time of the update, if
+    /// the result was still wanted and the effect succeeded.
+    pub fn complete(&mut self, result: EffectResult) -> Option<Time> {
+        self.rte.complete(result)
+    }
+
+    /// The underlying runtime, for everything not covered here.
+    pub fn runtime(&mut self) -> &mut RuntimeEnv {
+        &mut self.rte
+    }
+
+    pub fn into_runtime(self) -> RuntimeEnv {
+        self.rte
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    const SRC: &str = r#"mod main() {
+        warm = temperature > 20
+        label = if warm then "warm" else "cold"
+        "$label in $city"
+    }"#;
+
+    #[test]
+    fn test_embedding() {
+        let program = Program::compile(SRC, &["temperature", "city"]).unwrap();
+        let mut instance = program.instantiate().unwrap();
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let seen_cb = seen.clone();
+
+        instance.set_all(vec![("temperature", 25.into()), ("city", "Utrecht".into())]).unwrap();
+        instance
+            .subscribe("label", Box::new(move |_, v| seen_cb.borrow_mut().push(v.clone())))
+            .unwrap()
+            .detach();
+
+        assert_eq!(instance.result::<String>(), Ok("warm in Utrecht".to_string()));
+        assert_eq!(instance.get::<bool>("warm"), Ok(true));
+
+        instance.set("temperature", 12).unwrap();
+        assert_eq!(instance.result::<String>(), Ok("cold in Utrecht".to_string()));
+        assert_eq!(*seen.borrow(), vec![VarType::from("warm"), VarType::from("cold")]);
+
+        // Instances don't share state.
+        let mut other = program.instantiate().unwrap();
+        other.set_all(vec![("temperature", 30.into()), ("city", "Delft".into())]).unwrap();
+        assert_eq!(other.result::<String>(), Ok("warm in Delft".to_string()));
+        assert_eq!(instance.result::<String>(), Ok("cold in Utrecht".to_string()));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(Program::compile("mod main() {", &[]), Err(Error::Parse(_))));
+        assert_eq!(
+            Program::compile(SRC, &["temperature", "clock"]).err(),
+            Some(Error::Build("Input name is already taken."))
+        );
+
+        let mut instance = Program::compile(SRC, &["temperature", "city"]).unwrap().instantiate().unwrap();
+        instance.set("temperature", 25).unwrap();
+
+        assert_eq!(instance.set("pressure", 1), Err(Error::UnknownInput("pressure".to_string())));
+        assert_eq!(instance.get::<bool>("humid"), Err(Error::UnknownOutput("humid".to_string())));
+        assert_eq!(
+            instance.get::<i64>("warm"),
+            Err(Error::WrongType {
+                name: "warm".to_string(),
+                value: VarType::Bool(true)
+            })
+        );
+    }
+
+    #[test]
+    fn test_conversions() {
+        let v: VarType = vec![1i64, 2, 3].into();
+        assert_eq!(Vec::<i64>::try_from(v.clone()), Ok(vec![1, 2, 3]));
+        assert_eq!(Vec::<bool>::try_from(v.clone()), Err(v));
+        assert_eq!(String::try_from(VarType::from("abc")), Ok("abc".to_string()));
+        assert_eq!(char::try_from(VarType::from('x')), Ok('x'));
+    }
+
+    #[test]
+    fn test_parameters_and_outputs() {
+        let src = r#"mod main(mouse_x : int, label : str) {
+            out right = mouse_x > 100
+            out doubled = mouse_x + mouse_x
+            "$label: $mouse_x"
+        }"#;
+
+        let mut instance = Program::compile(src, &[]).unwrap().instantiate().unwrap();
+        instance
+            .set_all(vec![("mouse_x", 150.into()), ("label", "x".into())])
+            .unwrap();
+
+        assert_eq!(instance.get::<bool>("right"), Ok(true));
+        assert_eq!(instance.get::<i64>("doubled"), Ok(300));
+        assert_eq!(instance.result::<String>(), Ok("x: 150".to_string()));
+
+        let outputs: Vec<&str> = instance.runtime().outputs().map(|(name, _)| name).collect();
+        assert_eq!(outputs, vec!["doubled", "right", "stdout"]);
+    }
+
+    #[test]
+    fn test_typed_parameters() {
+        let mut instance = Program::compile("mod main(x : int) {\n x + 1\n}", &[])
+            .unwrap()
+            .instantiate()
+            .unwrap();
+
+        // Parameters have the default value of their type until they are set.
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let seen_cb = seen.clone();
+        instance
+            .subscribe_result(Box::new(move |_, v| seen_cb.borrow_mut().push(v.clone())))
+            .detach();
+        assert_eq!(instance.result::<i64>(), Ok(1));
+
+        assert_eq!(
+            instance.set("x", "two"),
+            Err(Error::WrongType {
+                name: "x".to_string(),
+                value: "two".into()
+            })
+        );
+        instance.set("x", 2).unwrap();
+        assert_eq!(*seen.borrow(), vec![VarType::Int(1), VarType::Int(3)]);
+
+        // Values put on the runtime directly are checked as well.
+        let rte = instance.runtime();
+        let x = rte.input("x").unwrap();
+        rte.put_current(x, VarType::Bool(true));
+        assert!(matches!(&rte.take_errors()[..], [RuntimeError::InputType { .. }]));
+        assert_eq!(instance.result::<i64>(), Ok(3));
+    }
+
+    #[test]
+    fn test_stream_result() {
+        let mut instance = Program::compile("mod main() {\n t = \"hello\"\n t[clock]\n}", &[])
+            .unwrap()
+            .instantiate()
+            .unwrap();
+
+        let printed = Rc::new(RefCell::new(String::new()));
+        let printed_cb = printed.clone();
+        instance
+            .stream_result(Box::new(move |_, v| {
+                if let VarType::Char(c) = v {
+                    printed_cb.borrow_mut().push(*c);
+                }
+            }))
+            .detach();
+
+        // The second l is printed although it equals the previous character.
+        for t in 1..5 {
+            instance.set("clock", t).unwrap();
+        }
+        assert_eq!(*printed.borrow(), "hello");
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut instance = Program::compile("mod main() {\n out line = stdin\n 1\n}", &[])
+            .unwrap()
+            .instantiate()
+            .unwrap();
+        assert_eq!(instance.outputs(), vec!["line".to_string()]);
+
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let lines_cb = lines.clone();
+        instance
+            .subscribe("line", Box::new(move |_, v| lines_cb.borrow_mut().push(v.clone())))
+            .unwrap()
+            .detach();
+
+        // Both lines are seen, one after the other.
+        instance.set_all(vec![("stdin", "a".into()), ("stdin", "b".into())]).unwrap();
+        assert_eq!(*lines.borrow(), vec![VarType::Null, "a".into(), "b".into()]);
+
+        let new = Program::compile("mod main() {\n out line = stdin\n 2\n}", &[]).unwrap();
+        let report = instance.reload(&new).unwrap();
+        assert!(report.kept > 0);
+        assert_eq!(instance.result::<i64>(), Ok(2));
+        assert_eq!(instance.get::<String>("line"), Ok("b".to_string()));
+    }
+
+    #[test]
+    fn test_natives() {
+        use crate::ast::Type;
+        use crate::program::{NativeError, Signature};
+
+        let shout = NativeFunction::new(
+            "shout",
+            Signature {
+                inputs: vec![Some(Type::PrimString)],
+                output: Some(Type::PrimString),
+            },
+            |args| match &args[0] {
+                VarType::Str(s) if !s.is_empty() => Ok(VarType::from(format!("{}!", s.to_uppercase()))),
+                _ => Err(NativeError("nothing to shout".to_string())),
+            },
+        );
+
+        let src = r#"mod main(word : str) {
+            shout(word)
+        }"#;
+
+        let program = Program::compile_with_natives(src, &[], vec![shout]).unwrap();
+
+        // Without a previous value, a failed call gives the default of its type.
+        let mut fresh = program.instantiate().unwrap();
+        assert_eq!(fresh.result::<String>(), Ok("".to_string()));
+        assert_eq!(fresh.take_errors().len(), 1);
+
+        let mut instance = program.instantiate().unwrap();
+        instance.set("word", "hey").unwrap();
+        assert_eq!(instance.result::<String>(), Ok("HEY!".to_string()));
+        assert_eq!(instance.take_errors(), vec![]);
+
+        // A failed call keeps the previous value.
+        instance.set("word", "").unwrap();
+        assert_eq!(instance.result::<String>(), Ok("HEY!".to_string()));
+        let errors = instance.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(&errors[0], RuntimeError::Native { function, .. } if function == "shout"));
+        assert!(errors[0].to_string().ends_with(": nothing to shout"));
+
+        // Applications are type checked against the signature.
+        let typed = |src: &str| {
+            let signature = Signature {
+                inputs: vec![Some(Type::PrimInt)],
+                output: None,
+            };
+            let f = NativeFunction::new("f", signature, |_| Ok(VarType::Null));
+            Program::compile_with_natives(src, &[], vec![f]).err()
+        };
+        assert_eq!(typed("mod main() {\n f(1)\n}"), None);
+        assert_eq!(typed("mod main() {\n f(\"a\")\n}"), Some(Error::Build("Argument has the wrong type.")));
+        assert_eq!(typed("mod main() {\n f(1, 2)\n}"), Some(Error::Build("Wrong number of arguments.")));
+    }
+}
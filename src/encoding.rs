@@ -0,0 +1,151 @@
+//! Plain-text encoding of values, for the files the runtime writes such as
+//! input logs and snapshots.
+//!
+//! A value is a kind followed by its contents, separated by spaces: `null`,
+//! `int 5`, `bool true`, `char 1 x`, `str 5 hello`, and `vec 2 int 1 int 2`.
+//! Strings are prefixed with their length once escaped, so that they may
+//! contain spaces while values can still be nested. Fragments are code rather
+//! than data, and are encoded by name only.
+
+use crate::program::VarType;
+use std::rc::Rc;
+
+pub fn encode_value(value: &VarType) -> String {
+    match value {
+        VarType::Null => "null".to_string(),
+        VarType::Int(i) => format!("int {}", i),
+        VarType::Bool(b) => format!("bool {}", b),
+        VarType::Char(c) => format!("char {}", encode_text(&c.to_string())),
+        VarType::Str(s) => format!("str {}", encode_text(s)),
+        VarType::Vector(v) => v
+            .iter()
+            .fold(format!("vec {}", v.len()), |acc, x| format!("{} {}", acc, encode_value(x))),
+        VarType::Fragment(f) => format!("fragment {}", encode_text(&f.name)),
+    }
+}
+
+/// Decode a value, or `None` if it isn't well-formed, has trailing input or is
+/// a fragment.
+pub fn decode_value(encoded: &str) -> Option<VarType> {
+    match decode_prefix(encoded)? {
+        (v, "") => Some(v),
+        _ => None,
+    }
+}
+
+fn encode_text(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    format!("{} {}", escaped.len(), escaped)
+}
+
+/// Split off the next space-separated token.
+fn token(s: &str) -> Option<(&str, &str)> {
+    if s.is_empty() {
+        return None;
+    }
+    Some(match s.find(' ') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, ""),
+    })
+}
+
+fn decode_text(s: &str) -> Option<(String, &str)> {
+    let (len, rest) = token(s)?;
+    let len: usize = len.parse().ok()?;
+    let escaped = rest.get(..len)?;
+    let rest = rest.get(len..)?;
+    let rest = rest.strip_prefix(' ').unwrap_or(rest);
+
+    let mut out = String::new();
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            out.push(match chars.next()? {
+                '\\' => '\\',
+                'n' => '\n',
+                'r' => '\r',
+                _ => return None,
+            });
+        } else {
+            out.push(c);
+        }
+    }
+    Some((out, rest))
+}
+
+/// Decode the value at the start of the input, returning the remaining input.
+fn decode_prefix(s: &str) -> Option<(VarType, &str)> {
+    let (kind, rest) = token(s)?;
+
+    match kind {
+        "null" => Some((VarType::Null, rest)),
+        "int" => {
+            let (i, rest) = token(rest)?;
+            Some((VarType::Int(i.parse().ok()?), rest))
+        }
+        "bool" => {
+            let (b, rest) = token(rest)?;
+            Some((VarType::Bool(b.parse().ok()?), rest))
+        }
+        "char" => {
+            let (text, rest) = decode_text(rest)?;
+            let mut chars = text.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Some((VarType::Char(c), rest)),
+                _ => None,
+            }
+        }
+        "str" => {
+            let (text, rest) = decode_text(rest)?;
+            Some((VarType::from_string(&text), rest))
+        }
+        "vec" => {
+            let (n, mut rest) = token(rest)?;
+            let n: usize = n.parse().ok()?;
+            let mut elements = Vec::with_capacity(n);
+            for _ in 0..n {
+                let (v, r) = decode_prefix(rest)?;
+                elements.push(v);
+                rest = r;
+            }
+            Some((VarType::Vector(Rc::new(elements)), rest))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        for v in vec![
+            VarType::Null,
+            VarType::Int(-3),
+            VarType::Bool(true),
+            VarType::Char(' '),
+            VarType::Char('\n'),
+            VarType::from_string("a \\n\nb"),
+            VarType::from_string(""),
+            VarType::Vector(Rc::new(vec![
+                VarType::from_string("x y"),
+                VarType::Vector(Rc::new(vec![])),
+                VarType::Int(7),
+            ])),
+        ] {
+            assert_eq!(decode_value(&encode_value(&v)), Some(v));
+        }
+    }
+
+    #[test]
+    fn test_rejects_malformed() {
+        assert_eq!(decode_value("str 10 abc"), None);
+        assert_eq!(decode_value("int 1 int 2"), None);
+        assert_eq!(decode_value("vec 2 int 1"), None);
+        assert_eq!(decode_value("fragment 3 abc"), None);
+    }
+}
@@ -0,0 +1,102 @@
+//! Recorded past values of a node, used to look up what a node was at some time.
+
+use crate::program::VarType;
+use crate::run::Time;
+use std::collections::VecDeque;
+
+/// How much history to keep for a node.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HistoryLimit {
+    /// Keep only the given number of most recent values.
+    Bounded(usize),
+    /// Keep every value the node ever had.
+    Unbounded,
+}
+
+/// The values of a node, each with the time from which on it held, oldest first.
+pub struct History {
+    limit: HistoryLimit,
+    entries: VecDeque<(Time, VarType)>,
+}
+
+impl History {
+    pub fn new(limit: HistoryLimit) -> History {
+        History {
+            limit,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Record that the node had the given value from `time` onwards.
+    /// Recomputations that produce the same value aren't recorded.
+    pub fn record(&mut self, time: Time, value: &VarType) {
+        if let Some((_, last)) = self.entries.back() {
+            if last == value {
+                return;
+            }
+        }
+
+        if let HistoryLimit::Bounded(n) = self.limit {
+            if n == 0 {
+                return;
+            }
+            while self.entries.len() >= n {
+                self.entries.pop_front();
+            }
+        }
+
+        self.entries.push_back((time, value.clone()));
+    }
+
+    /// The value at the given time, if it is still in the history.
+    pub fn value_at(&self, time: Time) -> Option<&VarType> {
+        // Entries are sorted by time, so find the last one not after `time`.
+        let after = self.entries.iter().take_while(|(t, _)| *t <= time).count();
+        if after == 0 {
+            None
+        } else {
+            Some(&self.entries[after - 1].1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_at() {
+        let mut h = History::new(HistoryLimit::Unbounded);
+        h.record(2, &VarType::Int(1));
+        h.record(3, &VarType::Int(1));
+        h.record(5, &VarType::Int(2));
+
+        assert_eq!(h.len(), 2);
+        assert_eq!(h.value_at(1), None);
+        assert_eq!(h.value_at(2), Some(&VarType::Int(1)));
+        assert_eq!(h.value_at(4), Some(&VarType::Int(1)));
+        assert_eq!(h.value_at(5), Some(&VarType::Int(2)));
+        assert_eq!(h.value_at(100), Some(&VarType::Int(2)));
+    }
+
+    #[test]
+    fn test_bounded_forgets_oldest() {
+        let mut h = History::new(HistoryLimit::Bounded(2));
+        for t in 0..5 {
+            h.record(t, &VarType::Int(t as i64));
+        }
+
+        assert_eq!(h.len(), 2);
+        assert_eq!(h.value_at(2), None);
+        assert_eq!(h.value_at(3), Some(&VarType::Int(3)));
+        assert_eq!(h.value_at(4), Some(&VarType::Int(4)));
+    }
+}
@@ -0,0 +1,88 @@
+//! Interactive inspector that steps through the ticks of a program and shows
+//! the value of every node at each of them.
+
+use crate::history::HistoryLimit;
+use crate::program::{NodeIndex, Operation, VarType};
+use crate::run::{RuntimeEnv, Time};
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "commands: [n]ext, [b]ack, [g]oto <time>, [i]nput <line>, [q]uit";
+
+pub fn inspect(mut rte: RuntimeEnv) {
+    let (stdout, stdin, clock) = (
+        rte.output("stdout").unwrap(),
+        rte.input("stdin").unwrap(),
+        rte.input("clock").unwrap(),
+    );
+
+    rte.record_all_history(HistoryLimit::Unbounded);
+    rte.pull_once(stdout);
+
+    let mut tick = 0;
+    let mut cursor = rte.current_time();
+
+    println!("{}", HELP);
+
+    loop {
+        print_state(&rte, cursor);
+        print!("> ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line).unwrap() == 0 {
+            return;
+        }
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        let (command, argument) = match line.find(' ') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => (line, ""),
+        };
+
+        match command {
+            "" | "n" | "next" => {
+                if cursor < rte.current_time() {
+                    cursor += 1;
+                } else {
+                    // Stepping past the latest time runs the program for another tick.
+                    tick += 1;
+                    cursor = rte.put_current(clock, VarType::Int(tick));
+                }
+            }
+            "b" | "back" => cursor = cursor.saturating_sub(1),
+            "g" | "goto" => match argument.parse::<Time>() {
+                Ok(t) => cursor = t.min(rte.current_time()),
+                Err(_) => println!("not a time: {}", argument),
+            },
+            "i" | "input" => cursor = rte.put_current(stdin, VarType::from_string(argument)),
+            "q" | "quit" => return,
+            _ => println!("{}", HELP),
+        }
+    }
+}
+
+fn print_state(rte: &RuntimeEnv, time: Time) {
+    println!("time {} (latest {})", time, rte.current_time());
+
+    for idx in rte.node_indices() {
+        let value = match rte.value_at(idx, time) {
+            Some(v) => v.describe(),
+            None => "-".to_string(),
+        };
+        println!(
+            "  {:>5} {:<30} {}",
+            node_number(idx),
+            describe_operation(rte.operation(idx)),
+            value
+        );
+    }
+}
+
+fn node_number(idx: NodeIndex) -> String {
+    format!("#{}", idx.0.into_raw_parts().0)
+}
+
+fn describe_operation(op: &Operation<NodeIndex>) -> String {
+    op.dependencies()
+        .into_iter()
+        .fold(op.label(), |acc, dep| format!("{} {}", acc, node_number(dep)))
+}
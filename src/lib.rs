@@ -0,0 +1,30 @@
+//! Tempura, a language for reactive programs that are built into dataflow
+//! graphs and updated incrementally as their inputs change.
+//!
+//! Programs can be embedded through [`Program`] and [`Instance`]; the other
+//! modules give access to the parser, builder and runtime underneath.
+
+extern crate nom;
+
+pub mod ast;
+pub mod build;
+pub mod code_from_ast;
+mod embed;
+pub mod effects;
+pub mod encoding;
+// mod gen_ast;
+pub mod history;
+pub mod inspect;
+mod memo;
+pub mod nom_parse;
+pub mod program;
+mod quoted_string;
+pub mod reload;
+pub mod replay;
+pub mod run;
+pub mod snapshot;
+pub mod verifier;
+
+pub use embed::{Error, Instance, Program};
+pub use program::{NativeError, NativeFunction, Signature, VarType};
+pub use run::{ListenerHandle, RuntimeEnv, RuntimeError, Time};
@@ -6,78 +6,201 @@ extern crate nom;
 // #[macro_use(quickcheck)]
 // extern crate quickcheck_macros;
 
-mod ast;
-mod build;
-// mod gen_ast;
-mod nom_parse;
-mod program;
-mod quoted_string;
-mod run;
-mod verifier;
-
 extern crate term_size;
 // extern crate pest;
 // #[macro_use]
 // extern crate pest_derive;
 
 use nom::error::VerboseError;
-use program::VarType;
+use tempura::code_from_ast::TempuraAST;
+use tempura::effects::{self, EffectHandler, EffectResult, SystemEffects};
+use tempura::program::VarType;
+use tempura::run::GraphFormat;
+use tempura::{inspect, nom_parse, replay, snapshot, Program};
 use std::io::{self, Write};
 use std::process::exit;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{env, fs};
 
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    println!("{:?}", args);
-    let filename = &args[1];
+    // Options of run: --resume <snapshot> to continue from a snapshot, and
+    // --snapshot <file> to save one after every update.
+    let options = ["--resume", "--snapshot"];
+    let option = |name: &str| {
+        args.iter()
+            .position(|a| a == name)
+            .and_then(|i| args.get(i + 1))
+            .cloned()
+    };
+    let (resume, save_snapshot) = (option("--resume"), option("--snapshot"));
+
+    // The arguments that are neither options nor their values.
+    let mut positional = Vec::new();
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        if options.contains(&arg.as_str()) {
+            rest.next();
+        } else {
+            positional.push(arg);
+        }
+    }
+
+    // The record and replay commands take the path of the log before the program.
+    let (command, log, filename) = match positional[0].as_str() {
+        "fmt" | "inspect" | "watch" | "run" | "graph" => (positional[0].as_str(), None, positional[1]),
+        "record" | "replay" => (positional[0].as_str(), Some(positional[1]), positional[2]),
+        _ => ("run", None, positional[0]),
+    };
 
     let contents = fs::read_to_string(filename).expect("Something went wrong reading the file");
 
-    let result = match nom_parse::parse_tempura::<VerboseError<&str>>(&contents) {
-        Ok((_, result)) => result,
+    if command == "fmt" {
+        match nom_parse::parse_tempura::<VerboseError<&str>>(&contents) {
+            Ok((_, result)) => println!("{}", result.gen_code()),
+            Err(e) => {
+                println!("{}", e);
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    // Progress goes to stderr so the output of graph can be redirected.
+    eprintln!("Building...");
+
+    let program = match Program::compile(&contents, &[]) {
+        Ok(program) => program,
         Err(e) => {
             println!("{}", e);
             exit(1);
         }
     };
 
-    println!("Building...");
+    for warning in program.warnings.iter() {
+        eprintln!("\u{001B}[33mWarning: {}\u{001B}[0m", warning);
+    }
+
+    let mut instance = program.instantiate().expect("Build failed.");
+
+    eprintln!("\u{001B}[32mBuild successful...");
 
-    let mut rte = build::build_runtime(result).expect("Build failed.");
+    // Prints the graph once the output is computed, as DOT unless --json is given,
+    // with fragment instances collapsed if --collapse is given.
+    if command == "graph" {
+        let rte = instance.runtime();
+        rte.pull_once(rte.output("stdout").unwrap());
+        let format = if args.iter().any(|a| a == "--json") {
+            GraphFormat::Json
+        } else {
+            GraphFormat::Dot
+        };
+        print!("{}", rte.export_graph(format, args.iter().any(|a| a == "--collapse")));
+        return;
+    }
+
+    if command == "inspect" {
+        inspect::inspect(instance.into_runtime());
+        return;
+    }
+
+    // The debugging and persistence tools work on the runtime directly.
+    let rte = instance.runtime();
 
-    // let stdout = main_module.0(vec![stdin], &mut rte);
+    if command == "replay" {
+        let log = fs::read_to_string(log.unwrap()).expect("Something went wrong reading the log");
+        match replay::replay(rte, &log) {
+            Ok(n) => println!("Replayed {} inputs, output matches.", n),
+            Err(e) => {
+                println!("Replay failed: {:?}", e);
+                exit(1);
+            }
+        }
+        return;
+    }
 
-    println!("\u{001B}[32mBuild successful...");
+    let resumed = resume.is_some();
+    if let Some(path) = resume {
+        let snapshot = fs::read_to_string(path).expect("Something went wrong reading the snapshot");
+        match snapshot::restore_snapshot(rte, &snapshot) {
+            Ok(report) => {
+                println!("Resumed at time {}: {:?}", rte.current_time(), report);
+            }
+            Err(e) => {
+                println!("Resume failed: {:?}", e);
+                exit(1);
+            }
+        }
+    }
+
+    if command == "record" {
+        let file = fs::File::create(log.unwrap()).expect("Something went wrong creating the log");
+        replay::start_recording(rte, Box::new(file));
+    }
+
+    // Keep counting from the clock value of a resumed program.
+    let first_tick = match rte.cached_value(rte.input("clock").unwrap()) {
+        Some((_, VarType::Int(t))) if resumed => *t as u64 + 1,
+        _ => 0,
+    };
 
-    rte.listen(
-        rte.stdout.unwrap(),
-        false,
-        Box::new(|_t, c| match c {
+    // Read the time once before the first tick, so that millis and wallclock
+    // are never null.
+    let started = Instant::now();
+    instance.set_all(time_inputs(started)).unwrap();
+
+    // The initial clock tick no longer re-notifies the output, since the clock
+    // already starts at 0, so print the current value when subscribing. Every
+    // recomputation is printed, so that repeated characters aren't dropped.
+    instance
+        .stream_result(Box::new(|_t, c| match c {
             VarType::Char(c) => {
                 print!("{}", c);
                 io::stdout().flush().unwrap();
             }
+            VarType::Str(s) => {
+                print!("{}", s);
+                io::stdout().flush().unwrap();
+            }
             VarType::Null => (),
-            _ => panic!("stdout should be char stream"),
-        }),
-    );
+            _ => panic!("stdout should be a char or string stream"),
+        }))
+        .detach();
+
+    // Other outputs declared with `out` are printed on a line of their own.
+    for name in instance.outputs() {
+        let label = name.clone();
+        instance
+            .subscribe(&name, Box::new(move |_t, v| println!("\n{} = {}", label, v.describe())))
+            .unwrap()
+            .detach();
+    }
 
     enum Event {
-        Stdin(char),
+        Stdin(String),
         ClockTick(u64),
+        SourceChanged(String),
+        Effect(EffectResult),
     }
 
     let (tx, rx): (Sender<Event>, Receiver<Event>) = mpsc::channel();
     let tx2 = tx.clone();
 
-    // Spawn one second timer
+    // Perform the effects the program asks for, and apply their results like
+    // other events.
+    let effects_tx = tx.clone();
+    let deliver = move |result| effects_tx.send(Event::Effect(result)).unwrap();
+    let mut system = SystemEffects::new();
+    effects::start_requested(instance.runtime(), &mut system, deliver.clone());
+
+    // Tick the clock every 100 ms. Timers at other rates, asked for with
+    // `every`, run as effects.
     thread::spawn(move || {
-        let mut t = 0;
+        let mut t = first_tick;
         loop {
             thread::sleep(Duration::from_millis(100));
             tx.send(Event::ClockTick(t)).unwrap();
@@ -85,27 +208,120 @@ fn main() {
         }
     });
 
-    // Spawn one second timer
+    // Poll the source file for changes in watch mode
+    if command == "watch" {
+        let tx3 = tx2.clone();
+        let path = filename.clone();
+        let modified = move || fs::metadata(&path).and_then(|m| m.modified()).ok();
+        let path = filename.clone();
+
+        thread::spawn(move || {
+            let mut last = modified();
+            loop {
+                thread::sleep(Duration::from_millis(500));
+                let now = modified();
+                if now != last {
+                    last = now;
+                    if let Ok(contents) = fs::read_to_string(&path) {
+                        tx3.send(Event::SourceChanged(contents)).unwrap();
+                    }
+                }
+            }
+        });
+    }
+
+    // Read stdin line by line
     thread::spawn(move || loop {
         let mut input = String::new();
         match io::stdin().read_line(&mut input) {
+            // End of input
+            Ok(0) => break,
             Ok(_n) => {
-                for c in input.chars() {
-                    tx2.send(Event::Stdin(c)).unwrap()
-                }
+                let line = input.trim_end_matches(&['\r', '\n'][..]);
+                tx2.send(Event::Stdin(line.to_string())).unwrap()
             }
             Err(error) => println!("error: {}", error),
         }
     });
 
     loop {
-        match rx.recv().unwrap() {
-            Event::Stdin(c) => {
-                rte.put_current(rte.stdin.unwrap(), VarType::Char(c));
+        // Apply everything that arrived since the last update at once, so that
+        // e.g. a line of input and a clock tick are seen at the same time.
+        let first = rx.recv().unwrap();
+        let events: Vec<Event> = std::iter::once(first).chain(rx.try_iter()).collect();
+
+        // Switch to a changed program before applying the inputs, keeping the
+        // running one if the new version doesn't build.
+        for event in events.iter() {
+            if let Event::SourceChanged(contents) = event {
+                match Program::compile(contents, &[]) {
+                    Ok(new) => {
+                        // The new program asks for the effects it needs again.
+                        let rte = instance.runtime();
+                        rte.cancel_effects();
+                        for id in rte.take_cancelled() {
+                            system.cancel(id);
+                        }
+
+                        let report = instance.reload(&new).expect("Build failed.");
+                        println!(
+                            "\nReloaded: kept {} nodes, reset {:?}",
+                            report.kept, report.reset
+                        );
+                        for warning in new.warnings.iter() {
+                            println!("Warning: {}", warning);
+                        }
+                    }
+                    Err(e) => println!("\nNot reloading: {}", e),
+                }
             }
-            Event::ClockTick(t) => {
-                rte.put_current(rte.clock.unwrap(), VarType::Int(t as i64));
+        }
+
+        let mut updates: Vec<(&str, VarType)> = Vec::new();
+        let mut results = Vec::new();
+
+        for event in events {
+            match event {
+                // Every line counts, while only the latest clock reading does.
+                Event::Stdin(line) => updates.push(("stdin", VarType::from_string(&line))),
+                Event::ClockTick(t) => {
+                    updates.retain(|(input, _)| *input == "stdin");
+                    updates.push(("clock", VarType::Int(t as i64)));
+                    updates.extend(time_inputs(started));
+                }
+                Event::Effect(result) => results.push(result),
+                Event::SourceChanged(_) => (),
             }
         }
+
+        instance.set_all(updates).unwrap();
+
+        for result in results {
+            instance.complete(result);
+        }
+
+        effects::start_requested(instance.runtime(), &mut system, deliver.clone());
+
+        for error in instance.take_errors() {
+            eprintln!("\n\u{001B}[31mError {}\u{001B}[0m", error);
+        }
+
+        if let Some(path) = &save_snapshot {
+            // Write to a temporary file first, so that a crash while writing
+            // doesn't leave a broken snapshot behind.
+            let tmp = format!("{}.tmp", path);
+            let mut file = fs::File::create(&tmp).expect("Something went wrong creating the snapshot");
+            snapshot::write_snapshot(instance.runtime(), &mut file).unwrap();
+            fs::rename(&tmp, path).unwrap();
+        }
     }
 }
+
+/// Values of the `millis` and `wallclock` inputs as of now.
+fn time_inputs(started: Instant) -> Vec<(&'static str, VarType)> {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    vec![
+        ("millis", VarType::Int(started.elapsed().as_millis() as i64)),
+        ("wallclock", VarType::Int(since_epoch.as_millis() as i64)),
+    ]
+}
@@ -0,0 +1,148 @@
+//! A bounded cache of the results of applying fragments to argument values.
+
+use crate::program::{Fragment, LacunaryRef, VarType};
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+/// Fragments are identified by the address of their `Rc`; the entry keeps the
+/// fragment alive so the address can't be reused while the entry exists.
+#[derive(PartialEq, Eq, Hash, Clone)]
+struct MemoKey {
+    fragment: *const Fragment<LacunaryRef>,
+    arguments: Vec<VarType>,
+}
+
+struct MemoEntry {
+    _fragment: Rc<Fragment<LacunaryRef>>,
+    value: VarType,
+    last_used: u64,
+}
+
+/// Least-recently-used cache of fragment application results.
+pub struct MemoCache {
+    capacity: usize,
+    use_counter: u64,
+    entries: HashMap<MemoKey, MemoEntry>,
+    by_last_use: BTreeMap<u64, MemoKey>,
+}
+
+impl MemoCache {
+    pub fn new(capacity: usize) -> MemoCache {
+        MemoCache {
+            capacity,
+            use_counter: 0,
+            entries: HashMap::new(),
+            by_last_use: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn lookup(
+        &mut self,
+        fragment: &Rc<Fragment<LacunaryRef>>,
+        arguments: &[VarType],
+    ) -> Option<VarType> {
+        let key = MemoKey {
+            fragment: Rc::as_ptr(fragment),
+            arguments: arguments.to_vec(),
+        };
+
+        self.use_counter += 1;
+        let now = self.use_counter;
+
+        let entry = self.entries.get_mut(&key)?;
+        self.by_last_use.remove(&entry.last_used);
+        entry.last_used = now;
+        self.by_last_use.insert(now, key);
+
+        Some(entry.value.clone())
+    }
+
+    pub fn insert(
+        &mut self,
+        fragment: &Rc<Fragment<LacunaryRef>>,
+        arguments: Vec<VarType>,
+        value: VarType,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = MemoKey {
+            fragment: Rc::as_ptr(fragment),
+            arguments,
+        };
+
+        self.use_counter += 1;
+        let now = self.use_counter;
+
+        if let Some(old) = self.entries.insert(
+            key.clone(),
+            MemoEntry {
+                _fragment: fragment.clone(),
+                value,
+                last_used: now,
+            },
+        ) {
+            self.by_last_use.remove(&old.last_used);
+        }
+        self.by_last_use.insert(now, key);
+
+        // Evict the least recently used entries until we're within bounds.
+        while self.entries.len() > self.capacity {
+            let (&oldest, _) = self.by_last_use.iter().next().unwrap();
+            let key = self.by_last_use.remove(&oldest).unwrap();
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Operation;
+    use std::collections::BTreeMap;
+
+    fn fragment() -> Rc<Fragment<LacunaryRef>> {
+        Rc::new(Fragment {
+            name: "id".to_string(),
+            nodes: vec![Operation::External],
+            output: LacunaryRef::InputRef { up: 0, index: 0 },
+            memoize: true,
+            names: BTreeMap::new(),
+            outputs: BTreeMap::new(),
+        })
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let f = fragment();
+        let mut cache = MemoCache::new(2);
+
+        cache.insert(&f, vec![VarType::Int(1)], VarType::Int(10));
+        cache.insert(&f, vec![VarType::Int(2)], VarType::Int(20));
+
+        // Touch 1, so that 2 is the least recently used.
+        assert_eq!(cache.lookup(&f, &[VarType::Int(1)]), Some(VarType::Int(10)));
+
+        cache.insert(&f, vec![VarType::Int(3)], VarType::Int(30));
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.lookup(&f, &[VarType::Int(2)]), None);
+        assert_eq!(cache.lookup(&f, &[VarType::Int(1)]), Some(VarType::Int(10)));
+        assert_eq!(cache.lookup(&f, &[VarType::Int(3)]), Some(VarType::Int(30)));
+    }
+
+    #[test]
+    fn test_distinguishes_fragments() {
+        let (f, g) = (fragment(), fragment());
+        let mut cache = MemoCache::new(10);
+
+        cache.insert(&f, vec![VarType::Int(1)], VarType::Int(10));
+
+        assert_eq!(cache.lookup(&g, &[VarType::Int(1)]), None);
+    }
+}
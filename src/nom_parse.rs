@@ -8,13 +8,14 @@ use nom::{
     branch::alt,
     bytes::complete::{tag, take_while, take_while1},
     character::complete::{char, digit1},
-    combinator::{map, opt},
-    multi::separated_list,
+    combinator::{map, map_opt, not, opt},
+    multi::{many0, separated_list, separated_nonempty_list},
     sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
     AsChar, IResult,
 };
 
 use crate::ast::*;
+use std::iter;
 
 // Whitespace
 
@@ -35,6 +36,18 @@ pub fn name<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&str, Name
 
 //region Expression
 
+/// Fold a chain of guarded branches and a final else branch into nested `IfElse`s.
+fn nest_branches(branches: Vec<(Expression, Expression)>, else_body: Expression) -> Expression {
+    branches
+        .into_iter()
+        .rev()
+        .fold(else_body, |else_body, (guard, body)| Expression::IfElse {
+            guard: Box::new(guard),
+            body: Box::new(body),
+            else_body: Box::new(else_body),
+        })
+}
+
 pub fn ifelse<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&'a str, Expression, E> {
     context(
         "if-then-else",
@@ -48,15 +61,99 @@ pub fn ifelse<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&'a str,
                     tuple((whitespace, tag("then"))),
                     context("if body", preceded(whitespace, expression)),
                 ),
+                many0(pair(
+                    preceded(
+                        tuple((whitespace, tag("elif"))),
+                        context("elif guard", preceded(whitespace, expression)),
+                    ),
+                    preceded(
+                        tuple((whitespace, tag("then"))),
+                        context("elif body", preceded(whitespace, expression)),
+                    ),
+                )),
                 preceded(
                     tuple((whitespace, tag("else"))),
                     context("else body", preceded(whitespace, expression)),
                 ),
+                opt(preceded(whitespace, tag("endif"))),
+            )),
+            |(guard, body, elifs, elsebody, _endif)| {
+                nest_branches(
+                    iter::once((guard, body)).chain(elifs.into_iter()).collect(),
+                    elsebody,
+                )
+            },
+        ),
+    )(src)
+}
+
+/// Multi-way conditional: `cond { g1 => e1, g2 => e2, else => e3 }`.
+pub fn cond<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&'a str, Expression, E> {
+    let branch = separated_pair(
+        context("cond guard", preceded(whitespace, expression)),
+        preceded(whitespace, tag("=>")),
+        context("cond body", preceded(whitespace, expression)),
+    );
+
+    let else_branch = preceded(
+        tuple((whitespace, tag("else"), whitespace, tag("=>"))),
+        context("cond else body", preceded(whitespace, expression)),
+    );
+
+    context(
+        "cond",
+        map(
+            preceded(
+                tuple((whitespace, tag("cond"), whitespace, char('{'))),
+                terminated(
+                    pair(
+                        many0(terminated(branch, preceded(whitespace, char(',')))),
+                        else_branch,
+                    ),
+                    tuple((opt(preceded(whitespace, char(','))), whitespace, char('}'))),
+                ),
+            ),
+            |(branches, else_body)| nest_branches(branches, else_body),
+        ),
+    )(src)
+}
+
+pub fn let_in<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&'a str, Expression, E> {
+    context(
+        "let-in",
+        map(
+            tuple((
+                preceded(whitespace, tag("let")),
+                context(
+                    "let bindings",
+                    separated_nonempty_list(preceded(whitespace, char(';')), assignment),
+                ),
+                preceded(whitespace, tag("in")),
+                context("let body", preceded(whitespace, expression)),
             )),
-            |(guard, body, elsebody)| Expression::IfElse {
-                guard: Box::new(guard),
+            |(_let, bindings, _in, body)| Expression::Let {
+                bindings,
+                body: Box::new(body),
+            },
+        ),
+    )(src)
+}
+
+pub fn block<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&'a str, Expression, E> {
+    context(
+        "block",
+        map(
+            delimited(
+                preceded(whitespace, char('{')),
+                pair(
+                    many0(terminated(assignment, preceded(whitespace, char(';')))),
+                    context("block body", preceded(whitespace, expression)),
+                ),
+                preceded(whitespace, char('}')),
+            ),
+            |(bindings, body)| Expression::Let {
+                bindings,
                 body: Box::new(body),
-                else_body: Box::new(elsebody),
             },
         ),
     )(src)
@@ -65,6 +162,38 @@ pub fn ifelse<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&'a str,
 enum AssigmentOrSubmodule {
     Assignment(AssignmentAST),
     Submodule(FragmentAST),
+    Assertion(ClaimAST),
+    Output(AssignmentAST),
+}
+
+/// An assignment exposed as an output of the program: `out <assignment>`.
+pub fn output_assignment<'a, E: ParseError<&'a str>>(
+    src: &'a str,
+) -> nom::IResult<&'a str, AssignmentAST, E> {
+    context(
+        "output",
+        preceded(pair(tag("out"), take_while1(|c| c == ' ' || c == '\t')), assignment),
+    )(src)
+}
+
+/// A claim about the values of a module: `assert <expression>` or
+/// `invariant <expression>`.
+pub fn assertion<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&'a str, ClaimAST, E> {
+    let kind = alt((
+        map(tag("assert"), |_| ClaimKind::Assert),
+        map(tag("invariant"), |_| ClaimKind::Invariant),
+    ));
+
+    context(
+        "assertion",
+        map(
+            pair(
+                terminated(kind, take_while1(|c| c == ' ' || c == '\t')),
+                expression,
+            ),
+            |(kind, claim)| ClaimAST { kind, claim },
+        ),
+    )(src)
 }
 
 pub fn module<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&'a str, FragmentAST, E> {
@@ -94,7 +223,12 @@ pub fn module<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&'a str,
             char('\n'),
             preceded(
                 whitespace,
-                alt((map(assignment, Assignment), map(module, Submodule))),
+                alt((
+                    map(assertion, Assertion),
+                    map(output_assignment, Output),
+                    map(assignment, Assignment),
+                    map(module, Submodule),
+                )),
             ),
         ),
     );
@@ -103,6 +237,7 @@ pub fn module<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&'a str,
         "module",
         map(
             tuple((
+                opt(preceded(whitespace, tag("nomemo"))),
                 preceded(whitespace, tag("mod")),
                 preceded(whitespace, name),
                 parameter_list,
@@ -111,15 +246,22 @@ pub fn module<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&'a str,
                 context("output expression", preceded(whitespace, expression)),
                 preceded(whitespace, char('}')),
             )),
-            |(_mod, name, inputs, _, decls, output, _)| {
+            |(nomemo, _mod, name, inputs, _, decls, output, _)| {
                 let mut assignments = Vec::new();
                 let mut submodules = Vec::new();
+                let mut assertions = Vec::new();
+                let mut outputs = Vec::new();
 
                 if let Some(d) = decls {
                     for dec in d {
                         match dec {
                             AssigmentOrSubmodule::Assignment(ass) => assignments.push(ass),
                             AssigmentOrSubmodule::Submodule(smod) => submodules.push(smod),
+                            AssigmentOrSubmodule::Assertion(claim) => assertions.push(claim),
+                            AssigmentOrSubmodule::Output(ass) => {
+                                outputs.push(ass.name.clone());
+                                assignments.push(ass);
+                            }
                         }
                     }
                 }
@@ -130,6 +272,9 @@ pub fn module<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&'a str,
                     assignments,
                     submodules,
                     output,
+                    memoize: nomemo.is_none(),
+                    assertions,
+                    outputs,
                 }
             },
         ),
@@ -142,6 +287,8 @@ pub fn ttype<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&'a str,
         alt((
             map(preceded(whitespace, tag("int")), |_| Type::PrimInt),
             map(preceded(whitespace, tag("str")), |_| Type::PrimString),
+            map(preceded(whitespace, tag("bool")), |_| Type::PrimBool),
+            map(preceded(whitespace, tag("char")), |_| Type::PrimChar),
         )),
     )(src)
 }
@@ -190,6 +337,9 @@ pub fn expression<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&str
         container_index,
         infix_operation,
         ifelse,
+        cond,
+        let_in,
+        block,
         single_expression,
     ))(src)
 }
@@ -211,13 +361,13 @@ pub fn valueref<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&str,
 }
 
 pub fn parse_int<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult<&str, i64, E> {
-    map(
+    map_opt(
         pair(opt(char('-')), digit1),
         |(sgn, digits): (Option<char>, &str)| {
             if sgn.is_some() {
-                -digits.parse::<i64>().unwrap()
+                format!("-{}", digits).parse::<i64>().ok()
             } else {
-                digits.parse::<i64>().unwrap()
+                digits.parse::<i64>().ok()
             }
         },
     )(src)
@@ -232,8 +382,8 @@ pub fn infix_operation<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult
         map(tag(">="), |_| BinaryOp::Geq),
         map(tag("=="), |_| BinaryOp::Eq),
         map(tag("<="), |_| BinaryOp::Leq),
-        map(tag(">"), |_| BinaryOp::Lt),
-        map(tag("<"), |_| BinaryOp::Gt)
+        map(tag(">"), |_| BinaryOp::Gt),
+        map(tag("<"), |_| BinaryOp::Lt)
     ));
 
     map(
@@ -243,11 +393,34 @@ pub fn infix_operation<'a, E: ParseError<&'a str>>(src: &'a str) -> nom::IResult
 }
 
 pub fn string<'a, E: ParseError<&'a str>>(src: &'a str) -> IResult<&str, Expression, E> {
-    map(quoted_string::parse_string, Expression::ConstString)(src)
+    map(quoted_string::parse_string, |mut parts| {
+        if parts.iter().all(|p| matches!(p, StringPart::Literal(_))) {
+            match parts.pop() {
+                Some(StringPart::Literal(s)) => Expression::ConstString(s),
+                _ => Expression::ConstString(String::new()),
+            }
+        } else {
+            Expression::Interpolation(parts)
+        }
+    })(src)
 }
 
+/// An integer, or a duration such as `250ms`, `2s` or `1min`, which is the
+/// integer number of milliseconds. A unit must end the word, and integers
+/// that don't fit in 64 bits don't parse.
 pub fn integer<'a, E: ParseError<&'a str>>(src: &'a str) -> IResult<&str, Expression, E> {
-    map(parse_int, Expression::ConstInteger)(src)
+    let unit = terminated(
+        alt((
+            map(tag("ms"), |_| 1),
+            map(tag("min"), |_| 60_000),
+            map(tag("s"), |_| 1000),
+        )),
+        not(take_while1(|c: char| c.is_alphanum() || c == '_')),
+    );
+
+    map_opt(pair(parse_int, opt(unit)), |(n, unit)| {
+        n.checked_mul(unit.unwrap_or(1)).map(Expression::ConstInteger)
+    })(src)
 }
 
 pub fn boolean<'a, E: ParseError<&'a str>>(src: &'a str) -> IResult<&str, Expression, E> {
@@ -309,6 +482,117 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_interpolation() {
+        assert_eq!(
+            string::<VerboseError<&str>>(r#""value: ${x + 1}, $y \$z""#),
+            Ok((
+                "",
+                Expression::Interpolation(vec![
+                    StringPart::Literal("value: ".to_string()),
+                    StringPart::Interpolated(Expression::BinaryOp(
+                        Box::new(Expression::LacunaryRef(Name("x".to_string()))),
+                        Box::new(Expression::ConstInteger(1)),
+                        BinaryOp::Sum
+                    )),
+                    StringPart::Literal(", ".to_string()),
+                    StringPart::Interpolated(Expression::LacunaryRef(Name("y".to_string()))),
+                    StringPart::Literal(" $z".to_string()),
+                ])
+            ))
+        );
+
+        assert_eq!(
+            string::<VerboseError<&str>>(r#""no \$interpolation""#),
+            Ok(("", Expression::ConstString("no $interpolation".to_string())))
+        );
+    }
+
+    #[test]
+    fn test_lone_dollar() {
+        for (src, text) in &[(r#""$5""#, "$5"), (r#""cost: $""#, "cost: $"), (r#""$ $$""#, "$ $$")] {
+            assert_eq!(
+                string::<VerboseError<&str>>(src),
+                Ok(("", Expression::ConstString(text.to_string())))
+            );
+        }
+        assert_eq!(
+            string::<VerboseError<&str>>(r#""$$x""#),
+            Ok((
+                "",
+                Expression::Interpolation(vec![
+                    StringPart::Literal("$".to_string()),
+                    StringPart::Interpolated(Expression::LacunaryRef(Name("x".to_string()))),
+                ])
+            ))
+        );
+        assert!(string::<VerboseError<&str>>(r#""${""#).is_err());
+    }
+
+    #[test]
+    fn test_let_in() {
+        let expected = Expression::Let {
+            bindings: vec![
+                assignment::<VerboseError<&str>>("a = x + 1").unwrap().1,
+                assignment::<VerboseError<&str>>("b = a").unwrap().1,
+            ],
+            body: Box::new(expression::<VerboseError<&str>>("a + b").unwrap().1),
+        };
+
+        assert_eq!(
+            let_in::<VerboseError<&str>>("let a = x + 1; b = a in a + b"),
+            Ok(("", expected.clone()))
+        );
+
+        assert_eq!(
+            expression::<VerboseError<&str>>("{ a = x + 1; b = a; a + b }"),
+            Ok(("", expected))
+        );
+    }
+
+    #[test]
+    fn test_elif_chain() {
+        let nested = ifelse::<VerboseError<&str>>(
+            "if a then x else if b then y else if c then z else w",
+        )
+        .unwrap()
+        .1;
+
+        assert_eq!(
+            ifelse::<VerboseError<&str>>(
+                "if a then x\n    elif b then y\n    elif c then z\n    else w\n    endif"
+            ),
+            Ok(("", nested.clone()))
+        );
+
+        assert_eq!(
+            cond::<VerboseError<&str>>("cond {\n  a => x,\n  b => y,\n  c => z,\n  else => w\n}"),
+            Ok(("", nested))
+        );
+    }
+
+    #[test]
+    fn test_comparison_operators() {
+        for (src, op) in &[
+            ("a > b", BinaryOp::Gt),
+            ("a < b", BinaryOp::Lt),
+            ("a >= b", BinaryOp::Geq),
+            ("a <= b", BinaryOp::Leq),
+        ] {
+            assert_eq!(
+                infix_operation::<VerboseError<&str>>(src),
+                Ok((
+                    "",
+                    Expression::BinaryOp(
+                        Box::new(Expression::LacunaryRef(Name("a".to_string()))),
+                        Box::new(Expression::LacunaryRef(Name("b".to_string()))),
+                        *op
+                    )
+                ))
+            );
+        }
+    }
+
     #[test]
     fn test_name() {
         assert_eq!(
@@ -412,6 +696,29 @@ mod tests {
         assert!(parse_int::<VerboseError<&str>>("  -13 37").is_err());
     }
 
+    #[test]
+    fn test_durations() {
+        assert_eq!(integer::<VerboseError<&str>>("250ms"), Ok(("", Expression::ConstInteger(250))));
+        assert_eq!(integer::<VerboseError<&str>>("2s"), Ok(("", Expression::ConstInteger(2000))));
+        assert_eq!(integer::<VerboseError<&str>>("1min"), Ok(("", Expression::ConstInteger(60_000))));
+
+        // Units end the word, so these are integers followed by something else.
+        assert_eq!(integer::<VerboseError<&str>>("5sec"), Ok(("sec", Expression::ConstInteger(5))));
+        assert_eq!(integer::<VerboseError<&str>>("3ms_x"), Ok(("ms_x", Expression::ConstInteger(3))));
+        assert_eq!(integer::<VerboseError<&str>>("2s)"), Ok((")", Expression::ConstInteger(2000))));
+
+        assert!(integer::<VerboseError<&str>>("9223372036854775807s").is_err());
+        assert!(integer::<VerboseError<&str>>("99999999999999999999").is_err());
+        assert_eq!(
+            integer::<VerboseError<&str>>("-9223372036854775808"),
+            Ok(("", Expression::ConstInteger(i64::MIN)))
+        );
+        assert_eq!(
+            expression::<VerboseError<&str>>("every(250ms)"),
+            expression::<VerboseError<&str>>("every(250)")
+        );
+    }
+
     #[test]
     fn test_module() {
         let src = r#"mod fb(i : int) {
@@ -434,6 +741,9 @@ mod tests {
                 )
                 .unwrap()
                 .1,
+                memoize: true,
+                assertions: vec![],
+                outputs: vec![],
             },
         );
     }
@@ -453,6 +763,44 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_assertions() {
+        let src = r#"mod main() {
+            x = 5
+            assert x >= 0
+            invariant x < 10
+            assert_free = 1
+            x
+        }"#;
+
+        let (_, modu) = module::<VerboseError<&str>>(src).unwrap();
+
+        let claim = |kind, src| ClaimAST {
+            kind,
+            claim: expression::<VerboseError<&str>>(src).unwrap().1,
+        };
+        assert_eq!(
+            modu.assertions,
+            vec![claim(ClaimKind::Assert, "x >= 0"), claim(ClaimKind::Invariant, "x < 10")]
+        );
+        assert_eq!(modu.assignments.len(), 2);
+    }
+
+    #[test]
+    fn test_outputs() {
+        let src = r#"mod main() {
+            out total = 5
+            outer = 1
+            out = 2
+            total
+        }"#;
+
+        let (_, modu) = module::<VerboseError<&str>>(src).unwrap();
+
+        assert_eq!(modu.outputs, vec![Name("total".to_string())]);
+        assert_eq!(modu.assignments.len(), 3);
+    }
+
     #[test]
     fn test_toplevel() {
         let src = r##"mod main(stdin : str) {
@@ -484,6 +832,9 @@ mod tests {
                 ],
                 assignments: vec![],
                 output: expression::<VerboseError<&str>>("fb(500)").unwrap().1,
+                memoize: true,
+                assertions: vec![],
+                outputs: vec![],
             },
         );
     }
@@ -1,18 +1,28 @@
 use generational_arena::Index;
 use itertools::join;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::iter;
 use std::rc::Rc;
-use crate::ast::BinaryOp;
+use crate::ast::{BinaryOp, ClaimKind, Type};
 
-#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
+#[derive(Debug, Eq, PartialEq, Hash, PartialOrd, Ord, Clone, Copy)]
 pub struct NodeIndex(pub Index);
 
-#[derive(Debug, Clone)]
+/// A runtime value.
+///
+/// Values are compared structurally. Values of different kinds are ordered by
+/// kind (in declaration order), strings and vectors lexicographically, and
+/// fragments by name and then by the structure of their graph, so that two
+/// fragments are equal exactly when they are built from the same definition
+/// with the same captured nodes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum VarType {
     Null,
     Int(i64),
     Bool(bool),
     Char(char),
+    Str(Rc<str>),
     Vector(Rc<Vec<VarType>>),
     Fragment(Rc<Fragment<LacunaryRef>>),
 }
@@ -42,6 +52,14 @@ impl VarType {
         }
     }
 
+    pub fn unpack_str(&self) -> Option<Rc<str>> {
+        if let VarType::Str(s) = self {
+            Some(s.clone())
+        } else {
+            None
+        }
+    }
+
     pub fn unpack_vector(&self) -> Option<Rc<Vec<VarType>>> {
         if let VarType::Vector(v) = self {
             Some(v.clone())
@@ -51,6 +69,9 @@ impl VarType {
     }
 
     pub fn stringify(&self) -> Option<String> {
+        if let VarType::Str(s) = self {
+            return Some(s.to_string());
+        }
         self.unpack_vector().and_then(|v| {
             let mut result = "".to_string();
             for c in v.iter() {
@@ -64,7 +85,7 @@ impl VarType {
     }
 
     pub fn from_string(string: &str) -> VarType {
-        VarType::Vector(Rc::new(string.chars().map(VarType::Char).collect()))
+        VarType::Str(Rc::from(string))
     }
 
     pub fn unpack_fragment(&self) -> Option<&Rc<Fragment<LacunaryRef>>> {
@@ -75,12 +96,43 @@ impl VarType {
         }
     }
 
+    /// The value an input of the given type has until one is put on it.
+    pub fn default_of(t: &Type) -> VarType {
+        match t {
+            Type::PrimInt => VarType::Int(0),
+            Type::PrimString => VarType::from(""),
+            Type::PrimBool => VarType::Bool(false),
+            Type::PrimChar => VarType::Char('\0'),
+        }
+    }
+
+    pub fn has_type(&self, t: &Type) -> bool {
+        matches!(
+            (self, t),
+            (VarType::Int(_), Type::PrimInt)
+                | (VarType::Str(_), Type::PrimString)
+                | (VarType::Bool(_), Type::PrimBool)
+                | (VarType::Char(_), Type::PrimChar)
+        )
+    }
+
+    /// A short description of the value for debugging output, which unlike
+    /// `render_as_string` quotes strings and doesn't print fragments in full.
+    pub fn describe(&self) -> String {
+        match self {
+            VarType::Fragment(f) => format!("<fragment {}>", f.name),
+            VarType::Str(s) => format!("{:?}", s),
+            _ => self.render_as_string(),
+        }
+    }
+
     pub fn render_as_string(&self) -> String {
         match self {
             VarType::Null => "null".to_string(),
             VarType::Int(i) => i.to_string(),
             VarType::Bool(b) => b.to_string(),
             VarType::Char(c) => c.to_string(),
+            VarType::Str(s) => s.to_string(),
             VarType::Fragment(f) => format!("{:?}", f),
             VarType::Vector(v) => {
                 format!("[{}]", join(v.iter().map(VarType::render_as_string), ","))
@@ -89,19 +141,332 @@ impl VarType {
     }
 }
 
+impl From<i64> for VarType {
+    fn from(i: i64) -> VarType {
+        VarType::Int(i)
+    }
+}
+
+impl From<bool> for VarType {
+    fn from(b: bool) -> VarType {
+        VarType::Bool(b)
+    }
+}
+
+impl From<char> for VarType {
+    fn from(c: char) -> VarType {
+        VarType::Char(c)
+    }
+}
+
+impl From<&str> for VarType {
+    fn from(s: &str) -> VarType {
+        VarType::from_string(s)
+    }
+}
+
+impl From<String> for VarType {
+    fn from(s: String) -> VarType {
+        VarType::from_string(&s)
+    }
+}
+
+impl<T: Into<VarType>> From<Vec<T>> for VarType {
+    fn from(v: Vec<T>) -> VarType {
+        VarType::Vector(Rc::new(v.into_iter().map(Into::into).collect()))
+    }
+}
+
+// Conversions back to Rust types give the value back if it has another type.
+
+impl TryFrom<VarType> for i64 {
+    type Error = VarType;
+
+    fn try_from(v: VarType) -> Result<i64, VarType> {
+        v.unpack_int().ok_or(v)
+    }
+}
+
+impl TryFrom<VarType> for bool {
+    type Error = VarType;
+
+    fn try_from(v: VarType) -> Result<bool, VarType> {
+        v.unpack_bool().ok_or(v)
+    }
+}
+
+impl TryFrom<VarType> for char {
+    type Error = VarType;
+
+    fn try_from(v: VarType) -> Result<char, VarType> {
+        v.unpack_char().ok_or(v)
+    }
+}
+
+impl TryFrom<VarType> for String {
+    type Error = VarType;
+
+    fn try_from(v: VarType) -> Result<String, VarType> {
+        match v {
+            VarType::Str(s) => Ok(s.to_string()),
+            v => Err(v),
+        }
+    }
+}
+
+impl<T: TryFrom<VarType, Error = VarType>> TryFrom<VarType> for Vec<T> {
+    type Error = VarType;
+
+    fn try_from(v: VarType) -> Result<Vec<T>, VarType> {
+        match &v {
+            VarType::Vector(items) => items.iter().cloned().map(T::try_from).collect::<Result<_, _>>().map_err(|_| v),
+            _ => Err(v),
+        }
+    }
+}
+
 use std::fmt::Debug;
 use std::hash::Hash;
 
-// #[derive(PartialEq, Eq, Debug)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum Operation<I: Clone + Copy + Debug> {
     External,
     Const(VarType),
     Vector(Vec<I>),
     BinaryOp(I, I, BinaryOp),
     ToString(I),
+    Sort(I),
+    StringOp(StringOp, Vec<I>),
     IfElse(I, I, I),
     ApplyFragment(I, Vec<I>),
+    /// Check a claim that couldn't be verified statically, given the claim,
+    /// the values it refers to and the value to pass on.
+    Check(Rc<Claim>, I, Vec<I>, I),
+    /// Call a function provided by the host.
+    Native(Rc<NativeFunction>, Vec<I>),
+    /// Ask the host to perform an effect with the given arguments. The value
+    /// is null until the host delivers the result.
+    Effect(EffectKind, Vec<I>),
+}
+
+/// Types of the arguments and result of a native function, where `None`
+/// accepts any value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Signature {
+    pub inputs: Vec<Option<Type>>,
+    pub output: Option<Type>,
+}
+
+/// A Rust function that programs can call by name, like the stdlib.
+///
+/// Native functions should be pure, since the runtime only calls them again
+/// when their arguments change, and may memoize their results.
+pub struct NativeFunction {
+    pub name: String,
+    pub signature: Signature,
+    function: Box<dyn Fn(&[VarType]) -> Result<VarType, NativeError>>,
+}
+
+/// Why a native function failed, reported by the runtime together with where
+/// and when it was called.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NativeError(pub String);
+
+impl NativeFunction {
+    pub fn new(
+        name: &str,
+        signature: Signature,
+        function: impl Fn(&[VarType]) -> Result<VarType, NativeError> + 'static,
+    ) -> NativeFunction {
+        NativeFunction {
+            name: name.to_string(),
+            signature,
+            function: Box::new(function),
+        }
+    }
+
+    pub fn call(&self, args: &[VarType]) -> Result<VarType, NativeError> {
+        (self.function)(args)
+    }
+}
+
+// Native functions are registered under unique names, so they are compared
+// and hashed by name.
+
+impl Debug for NativeFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NativeFunction({})", self.name)
+    }
+}
+
+impl PartialEq for NativeFunction {
+    fn eq(&self, other: &NativeFunction) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for NativeFunction {}
+
+impl Hash for NativeFunction {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.name.hash(state)
+    }
+}
+
+impl PartialOrd for NativeFunction {
+    fn partial_cmp(&self, other: &NativeFunction) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NativeFunction {
+    fn cmp(&self, other: &NativeFunction) -> std::cmp::Ordering {
+        self.name.cmp(&other.name)
+    }
+}
+
+/// A claim from the source to be checked at runtime.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Claim {
+    pub kind: ClaimKind,
+    /// The claim as written.
+    pub source: String,
+    /// Names of the values the claim refers to, reported when it fails.
+    pub operands: Vec<String>,
+}
+
+/// Primitive operations on strings, exposed to programs through the stdlib.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum StringOp {
+    Split,
+    Trim,
+    Substr,
+    Find,
+    Replace,
+    ToUpper,
+    ParseInt,
+    /// Length of a string in characters, or of a vector.
+    Len,
+    /// Element of a string or vector at an index, or a default if the index
+    /// is out of bounds.
+    Get,
+    /// An integer limited to a range.
+    Clamp,
+}
+
+impl StringOp {
+    pub const ALL: [StringOp; 10] = [
+        StringOp::Split,
+        StringOp::Trim,
+        StringOp::Substr,
+        StringOp::Find,
+        StringOp::Replace,
+        StringOp::ToUpper,
+        StringOp::ParseInt,
+        StringOp::Len,
+        StringOp::Get,
+        StringOp::Clamp,
+    ];
+
+    /// Name under which the operation is available in the stdlib.
+    pub fn name(&self) -> &'static str {
+        use StringOp::*;
+        match self {
+            Split => "split",
+            Trim => "trim",
+            Substr => "substr",
+            Find => "find",
+            Replace => "replace",
+            ToUpper => "to_upper",
+            ParseInt => "parse_int",
+            Len => "len",
+            Get => "get",
+            Clamp => "clamp",
+        }
+    }
+
+    pub fn arity(&self) -> usize {
+        use StringOp::*;
+        match self {
+            Trim | ToUpper | ParseInt | Len => 1,
+            Split | Find => 2,
+            Substr | Replace | Get | Clamp => 3,
+        }
+    }
+}
+
+/// Effects programs can ask the host to perform, exposed through the stdlib.
+/// See `crate::effects` for how hosts perform them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum EffectKind {
+    /// Contents of the file at a path.
+    ReadFile,
+    /// Output of a shell command.
+    Run,
+    /// True once a number of milliseconds has passed.
+    Timer,
+    /// Contents of the file at a path, kept up to date as it changes.
+    FileContents,
+    /// Sorted names of the entries of a directory, kept up to date.
+    DirListing,
+    /// Number of times a number of milliseconds has passed.
+    Every,
+}
+
+impl EffectKind {
+    pub const ALL: [EffectKind; 6] = [
+        EffectKind::ReadFile,
+        EffectKind::Run,
+        EffectKind::Timer,
+        EffectKind::FileContents,
+        EffectKind::DirListing,
+        EffectKind::Every,
+    ];
+
+    /// Name under which the effect is available in the stdlib.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EffectKind::ReadFile => "read_file",
+            EffectKind::Run => "run",
+            EffectKind::Timer => "after",
+            EffectKind::FileContents => "file_contents",
+            EffectKind::DirListing => "dir_listing",
+            EffectKind::Every => "every",
+        }
+    }
+
+    /// Whether the host keeps delivering results as what the effect looks at
+    /// changes, until the request is cancelled.
+    pub fn is_watch(&self) -> bool {
+        matches!(self, EffectKind::FileContents | EffectKind::DirListing | EffectKind::Every)
+    }
+
+    /// Results of files, directories and commands are null while pending or
+    /// after failing, so only timers have a known type.
+    pub fn signature(&self) -> Signature {
+        let (input, output) = match self {
+            EffectKind::ReadFile | EffectKind::FileContents | EffectKind::DirListing => {
+                (Type::PrimString, None)
+            }
+            EffectKind::Run => (Type::PrimString, None),
+            EffectKind::Timer => (Type::PrimInt, Some(Type::PrimBool)),
+            EffectKind::Every => (Type::PrimInt, Some(Type::PrimInt)),
+        };
+
+        Signature {
+            inputs: vec![Some(input)],
+            output,
+        }
+    }
+
+    /// The value while the result isn't known yet.
+    pub fn pending_value(&self) -> VarType {
+        match self {
+            EffectKind::Timer => VarType::Bool(false),
+            EffectKind::Every => VarType::Int(0),
+            _ => VarType::Null,
+        }
+    }
 }
 
 impl<I: Copy + Debug> Operation<I> {
@@ -113,15 +478,42 @@ impl<I: Copy + Debug> Operation<I> {
             Vector(v) => v.clone(),
             BinaryOp(a,b,_) => vec![*a, *b],
             ToString(a) => vec![*a],
+            Sort(a) => vec![*a],
+            StringOp(_, args) => args.clone(),
             IfElse(a, b, c) => vec![*a, *b, *c],
             ApplyFragment(f, args) => iter::once(*f).chain(args.iter().cloned()).collect(),
+            Native(_, args) => args.clone(),
+            Effect(_, args) => args.clone(),
+            Check(_, claim, operands, output) => iter::once(*claim)
+                .chain(operands.iter().cloned())
+                .chain(iter::once(*output))
+                .collect(),
+        }
+    }
+
+    /// What the operation does, without its operands.
+    pub fn label(&self) -> String {
+        use Operation::*;
+        match self {
+            External => "external".to_string(),
+            Const(_) => "const".to_string(),
+            Vector(_) => "vector".to_string(),
+            BinaryOp(_, _, opr) => format!("{:?}", opr),
+            ToString(_) => "to_string".to_string(),
+            Sort(_) => "sort".to_string(),
+            StringOp(sop, _) => sop.name().to_string(),
+            IfElse(_, _, _) => "if".to_string(),
+            ApplyFragment(_, _) => "apply".to_string(),
+            Check(claim, _, _, _) => claim.kind.keyword().to_string(),
+            Native(f, _) => f.name.clone(),
+            Effect(kind, _) => kind.name().to_string(),
         }
     }
 }
 
 // pub struct RuntimeModule(pub Box<dyn Fn(Vec<NodeIndex>, &mut RuntimeEnv) -> NodeIndex>);
 
-#[derive(Eq, PartialEq, Hash, Copy, Clone, Debug)]
+#[derive(Eq, PartialEq, Hash, PartialOrd, Ord, Copy, Clone, Debug)]
 pub enum LacunaryRef {
     InputRef { up: usize, index: usize },
     ContextRef { up: usize, index: usize },
@@ -147,13 +539,19 @@ pub trait Lacunary<F> {
     fn finalize(self) -> F;
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Fragment<I: Copy + Debug> {
     // TODO maybe add an index of stuff to be filled in?
     // Current algorithm is kinda expensive.
     pub name: String,
     pub nodes: Vec<Operation<I>>,
     pub output: I,
+    /// Whether the runtime may memoize applications of this fragment.
+    pub memoize: bool,
+    /// Names given to nodes in the source, by position in `nodes`.
+    pub names: BTreeMap<usize, String>,
+    /// Values declared with `out`, by name.
+    pub outputs: BTreeMap<String, I>,
 }
 
 impl Lacunary<Fragment<NodeIndex>> for Fragment<LacunaryRef> {
@@ -171,6 +569,13 @@ impl Lacunary<Fragment<NodeIndex>> for Fragment<LacunaryRef> {
                 .map(|n| n.fill_in(nodes, inputs, depth))
                 .collect(),
             output: self.output.fill_in(nodes, inputs, depth),
+            memoize: self.memoize,
+            names: self.names.clone(),
+            outputs: self
+                .outputs
+                .iter()
+                .map(|(name, o)| (name.clone(), o.fill_in(nodes, inputs, depth)))
+                .collect(),
         }
     }
 
@@ -183,6 +588,9 @@ impl Lacunary<Fragment<NodeIndex>> for Fragment<LacunaryRef> {
             //     _ => panic!("cannot finalize with remaining hole")
             // }).collect(),
             output: self.output.finalize(),
+            memoize: self.memoize,
+            names: self.names,
+            outputs: self.outputs.into_iter().map(|(name, o)| (name, o.finalize())).collect(),
         }
     }
 }
@@ -210,6 +618,13 @@ impl Lacunary<Operation<NodeIndex>> for Operation<LacunaryRef> {
                 *op
             ),
             ToString(a) => ToString(a.fill_in(indices, inputs, depth)),
+            Sort(a) => Sort(a.fill_in(indices, inputs, depth)),
+            StringOp(op, args) => StringOp(
+                *op,
+                args.iter()
+                    .map(|n| n.fill_in(indices, inputs, depth))
+                    .collect(),
+            ),
             IfElse(a, b, c) => IfElse(
                 a.fill_in(indices, inputs, depth),
                 b.fill_in(indices, inputs, depth),
@@ -220,7 +635,28 @@ impl Lacunary<Operation<NodeIndex>> for Operation<LacunaryRef> {
                 args.iter()
                     .map(|n| n.fill_in(indices, inputs, depth))
                     .collect(),
-            )
+            ),
+            Native(f, args) => Native(
+                f.clone(),
+                args.iter()
+                    .map(|n| n.fill_in(indices, inputs, depth))
+                    .collect(),
+            ),
+            Effect(kind, args) => Effect(
+                *kind,
+                args.iter()
+                    .map(|n| n.fill_in(indices, inputs, depth))
+                    .collect(),
+            ),
+            Check(claim, c, operands, output) => Check(
+                claim.clone(),
+                c.fill_in(indices, inputs, depth),
+                operands
+                    .iter()
+                    .map(|n| n.fill_in(indices, inputs, depth))
+                    .collect(),
+                output.fill_in(indices, inputs, depth),
+            ),
         }
     }
 
@@ -233,10 +669,20 @@ impl Lacunary<Operation<NodeIndex>> for Operation<LacunaryRef> {
             Vector(v) => Vector(v.iter().map(|n| n.finalize()).collect()),
             BinaryOp(a, b, op) => BinaryOp(a.finalize(), b.finalize(), op),
             ToString(a) => ToString(a.finalize()),
+            Sort(a) => Sort(a.finalize()),
+            StringOp(op, args) => StringOp(op, args.iter().map(|n| n.finalize()).collect()),
             IfElse(a, b, c) => IfElse(a.finalize(), b.finalize(), c.finalize()),
             ApplyFragment(f, args) => {
                 ApplyFragment(f.finalize(), args.iter().map(|n| n.finalize()).collect())
             }
+            Native(f, args) => Native(f, args.iter().map(|n| n.finalize()).collect()),
+            Effect(kind, args) => Effect(kind, args.iter().map(|n| n.finalize()).collect()),
+            Check(claim, c, operands, output) => Check(
+                claim,
+                c.finalize(),
+                operands.iter().map(|n| n.finalize()).collect(),
+                output.finalize(),
+            ),
         }
     }
 }
@@ -251,6 +697,7 @@ impl Lacunary<VarType> for VarType {
             VarType::Bool(b) => VarType::Bool(*b),
             VarType::Int(i) => VarType::Int(*i),
             VarType::Char(c) => VarType::Char(*c),
+            VarType::Str(s) => VarType::Str(s.clone()),
             VarType::Vector(v) => VarType::Vector(Rc::new(
                 v.iter()
                     .map(|n| n.fill_in(indices, inputs, depth))
@@ -266,6 +713,7 @@ impl Lacunary<VarType> for VarType {
             VarType::Bool(b) => VarType::Bool(b),
             VarType::Int(i) => VarType::Int(i),
             VarType::Char(c) => VarType::Char(c),
+            VarType::Str(s) => VarType::Str(s),
             VarType::Vector(v) => {
                 VarType::Vector(Rc::new(v.iter().cloned().map(|n| n.finalize()).collect()))
             }
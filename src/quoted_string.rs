@@ -5,23 +5,28 @@
 //!
 //! - Enclosed by double quotes
 //! - Can contain any raw unescaped code point besides \ and "
-//! - Matches the following escape sequences: \b, \f, \n, \r, \t, \", \\, \/
+//! - Matches the following escape sequences: \b, \f, \n, \r, \t, \", \\, \/, \$
 //! - Matches code points like Rust: \u{XXXX}, where XXXX can be up to 6
 //!   hex characters
 //! - an escape followed by whitespace consumes all whitespace between the
 //!   escape and the next non-whitespace character
+//! - `$name` and `${expression}` interpolate a value into the string; any
+//!   other `$` is literal text
 
 extern crate nom;
 
 use nom::branch::alt;
-use nom::bytes::streaming::{is_not, take_while_m_n};
+use nom::bytes::streaming::{is_not, tag, take_while_m_n};
 use nom::character::streaming::{char, multispace1};
-use nom::combinator::{map, map_opt, map_res, value, verify};
+use nom::combinator::{map, map_opt, map_res, not, value, verify};
 use nom::error::ParseError;
 use nom::multi::fold_many0;
-use nom::sequence::{delimited, preceded};
+use nom::sequence::{delimited, preceded, terminated};
 use nom::IResult;
 
+use crate::ast::{Expression, Name, StringPart};
+use crate::nom_parse::{expression, name, whitespace};
+
 // parser combinators are constructed from the bottom up:
 // first we write parsers for the smallest elements (escaped characters),
 // then combine them into larger parsers.
@@ -76,6 +81,7 @@ fn parse_escaped_char<'a, E: ParseError<&'a str>>(input: &'a str) -> IResult<&'a
             value('\\', char('\\')),
             value('/', char('/')),
             value('"', char('"')),
+            value('$', char('$')),
         )),
     )(input)
 }
@@ -88,11 +94,11 @@ fn parse_escaped_whitespace<'a, E: ParseError<&'a str>>(
     preceded(char('\\'), multispace1)(input)
 }
 
-/// Parse a non-empty block of text that doesn't include \ or "
+/// Parse a non-empty block of text that doesn't include \, " or $
 fn parse_literal<'a, E: ParseError<&'a str>>(input: &'a str) -> IResult<&'a str, &'a str, E> {
     // `is_not` parses a string of 0 or more characters that aren't one of the
     // given characters.
-    let not_quote_slash = is_not("\"\\");
+    let not_quote_slash = is_not("\"\\$");
 
     // `verify` runs a parser, then runs a verification function on the output of
     // the parser. The verification function accepts out output only if it
@@ -101,14 +107,42 @@ fn parse_literal<'a, E: ParseError<&'a str>>(input: &'a str) -> IResult<&'a str,
     verify(not_quote_slash, |s: &str| !s.is_empty())(input)
 }
 
+/// Parse an interpolation, either `$name` or `${expression}`.
+fn parse_interpolation<'a, E: ParseError<&'a str>>(
+    input: &'a str,
+) -> IResult<&'a str, Expression, E> {
+    preceded(
+        char('$'),
+        alt((
+            delimited(
+                char('{'),
+                preceded(whitespace, expression),
+                preceded(whitespace, char('}')),
+            ),
+            map(
+                verify(name, |n: &Name| !n.0.starts_with(|c: char| c.is_ascii_digit())),
+                Expression::LacunaryRef,
+            ),
+        )),
+    )(input)
+}
+
+/// Parse a `$` that doesn't start an interpolation, as in `"$5"` or
+/// `"cost: $"`. It stands for itself.
+fn parse_lone_dollar<'a, E: ParseError<&'a str>>(input: &'a str) -> IResult<&'a str, &'a str, E> {
+    terminated(tag("$"), not(char('{')))(input)
+}
+
 /// A string fragment contains a fragment of a string being parsed: either
 /// a non-empty Literal (a series of non-escaped characters), a single
-/// parsed escaped character, or a block of escaped whitespace.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// parsed escaped character, a block of escaped whitespace or an
+/// interpolated expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum StringFragment<'a> {
     Literal(&'a str),
     EscapedChar(char),
     EscapedWS,
+    Interpolation(Expression),
 }
 
 /// Combine parse_literal, parse_escaped_whitespace, and parse_escaped_char
@@ -122,28 +156,41 @@ fn parse_fragment<'a, E: ParseError<&'a str>>(
         map(parse_literal, StringFragment::Literal),
         map(parse_escaped_char, StringFragment::EscapedChar),
         value(StringFragment::EscapedWS, parse_escaped_whitespace),
+        map(parse_interpolation, StringFragment::Interpolation),
+        map(parse_lone_dollar, StringFragment::Literal),
     ))(input)
 }
 
+/// Append a piece of literal text, merging it with a preceding literal part.
+fn push_text(parts: &mut Vec<StringPart>, text: &str) {
+    match parts.last_mut() {
+        Some(StringPart::Literal(s)) => s.push_str(text),
+        _ => parts.push(StringPart::Literal(text.to_string())),
+    }
+}
+
 /// Parse a string. Use a loop of parse_fragment and push all of the fragments
-/// into an output string.
-pub fn parse_string<'a, E: ParseError<&'a str>>(input: &'a str) -> IResult<&'a str, String, E> {
+/// into a list of literal text and interpolated expressions.
+pub fn parse_string<'a, E: ParseError<&'a str>>(
+    input: &'a str,
+) -> IResult<&'a str, Vec<StringPart>, E> {
     // fold_many0 is the equivalent of iterator::fold. It runs a parser in a loop,
     // and for each output value, calls a folding function on each output value.
     let build_string = fold_many0(
         // Our parser function– parses a single string fragment
         parse_fragment,
-        // Our init value, an empty string
-        String::new(),
+        // Our init value, an empty list of parts
+        Vec::new(),
         // Our folding function. For each fragment, append the fragment to the
-        // string.
-        |mut string, fragment| {
+        // last literal part, or start a new part for an interpolation.
+        |mut parts, fragment| {
             match fragment {
-                StringFragment::Literal(s) => string.push_str(s),
-                StringFragment::EscapedChar(c) => string.push(c),
+                StringFragment::Literal(s) => push_text(&mut parts, s),
+                StringFragment::EscapedChar(c) => push_text(&mut parts, &c.to_string()),
                 StringFragment::EscapedWS => {}
+                StringFragment::Interpolation(e) => parts.push(StringPart::Interpolated(e)),
             }
-            string
+            parts
         },
     );
 
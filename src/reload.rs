@@ -0,0 +1,163 @@
+//! Replacing a running program with a rebuilt version of it, keeping the
+//! state of everything whose definition didn't change.
+
+use crate::program::{NodeIndex, Operation};
+use crate::run::RuntimeEnv;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ReloadReport {
+    /// Number of nodes whose definition didn't change, and that kept their
+    /// listeners and history.
+    pub kept: usize,
+    /// Nodes that are new or whose definition changed, by stable id.
+    pub reset: Vec<String>,
+    /// Nodes of the old program that weren't kept, by stable id.
+    pub removed: Vec<String>,
+}
+
+/// Switch from the running program `old` to the freshly built `new`.
+///
+/// Nodes are matched by their stable ids, and considered unchanged if their
+/// signatures are equal as well. The values of external nodes carry over, and
+/// everything else is recomputed from them, so unchanged nodes end up with the
+/// values they had. Listeners on outputs always move to the new output with
+/// the same name.
+pub fn reload(mut old: RuntimeEnv, mut new: RuntimeEnv) -> (RuntimeEnv, ReloadReport) {
+    let mut report = ReloadReport::default();
+
+    let old_by_id: HashMap<String, NodeIndex> = old
+        .node_indices()
+        .map(|idx| (old.stable_id(idx).to_string(), idx))
+        .collect();
+
+    let externals = new
+        .node_indices()
+        .filter(|idx| *new.operation(*idx) == Operation::External)
+        .filter_map(|idx| {
+            let old_idx = *old_by_id.get(new.stable_id(idx))?;
+            let (t, value) = old.cached_value(old_idx)?;
+            Some((idx, t, value.clone()))
+        })
+        .collect();
+
+    new.restore(old.current_time(), externals);
+
+    if let Some(recorder) = old.take_input_recorder() {
+        new.record_inputs(recorder);
+    }
+
+    // Instantiate the fragments applied by the new program, so that their
+    // nodes can be matched too.
+    for output in new.outputs().map(|(_, idx)| idx).collect::<Vec<_>>() {
+        new.pull_once(output);
+    }
+
+    let mut matched = HashSet::new();
+    for idx in new.node_indices().collect::<Vec<_>>() {
+        match old_by_id.get(new.stable_id(idx)) {
+            Some(old_idx) if old.signature(*old_idx) == new.signature(idx) => {
+                new.adopt_node_state(idx, &mut old, *old_idx);
+                matched.insert(*old_idx);
+                report.kept += 1;
+            }
+            _ => report.reset.push(new.stable_id(idx).to_string()),
+        }
+    }
+
+    let outputs: Vec<(String, NodeIndex)> = old.outputs().map(|(name, idx)| (name.to_string(), idx)).collect();
+    for (name, old_out) in outputs {
+        if let Some(new_out) = new.output(&name) {
+            if !matched.contains(&old_out) {
+                new.adopt_node_state(new_out, &mut old, old_out);
+            }
+        }
+    }
+
+    for idx in old.node_indices() {
+        if !matched.contains(&idx) {
+            report.removed.push(old.stable_id(idx).to_string());
+        }
+    }
+
+    report.reset.sort();
+    report.removed.sort();
+
+    (new, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::build_runtime;
+    use crate::nom_parse::parse_tempura;
+    use crate::program::VarType;
+    use crate::run::ListenMode;
+    use nom::error::VerboseError;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn runtime(src: &str) -> RuntimeEnv {
+        build_runtime(parse_tempura::<VerboseError<&str>>(src).unwrap().1).unwrap()
+    }
+
+    const SRC: &str = r#"mod main() {
+        total = clock + 100
+        label = "count"
+        "$label: $total"
+    }"#;
+
+    #[test]
+    fn test_reload_keeps_unchanged_nodes() {
+        let mut old = runtime(SRC);
+        let stdout = old.output("stdout").unwrap();
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let seen_cb = seen.clone();
+        let total_handle = old.listen(
+            old.find_node("main/total").unwrap(),
+            false,
+            ListenMode::OnChange,
+            Box::new(|_, _| ()),
+        );
+        old.listen(
+            stdout,
+            false,
+            ListenMode::OnChange,
+            Box::new(move |_, v| seen_cb.borrow_mut().push(v.clone())),
+        )
+        .detach();
+
+        old.put_current(old.input("clock").unwrap(), VarType::Int(5));
+        let time = old.current_time();
+
+        let (mut new, report) = reload(old, runtime(&SRC.replace("count", "ticks")));
+
+        assert_eq!(new.current_time(), time);
+        assert!(report.kept > 0);
+        assert!(report.reset.contains(&"main/label".to_string()));
+        assert!(report.removed.contains(&"main/label".to_string()));
+        assert!(!report.reset.contains(&"main/total".to_string()));
+        assert!(total_handle.is_active());
+
+        // The clock kept its value, and the output listener moved along.
+        assert_eq!(new.pull_once(new.output("stdout").unwrap()), VarType::from_string("ticks: 105"));
+        new.put_current(new.input("clock").unwrap(), VarType::Int(6));
+        assert_eq!(
+            seen.borrow().last(),
+            Some(&VarType::from_string("ticks: 106"))
+        );
+    }
+
+    #[test]
+    fn test_reload_unchanged_program() {
+        let mut old = runtime(SRC);
+        old.put_current(old.input("clock").unwrap(), VarType::Int(5));
+        old.pull_once(old.output("stdout").unwrap());
+
+        let (_, report) = reload(old, runtime(SRC));
+
+        assert_eq!(report.reset, Vec::<String>::new());
+        assert_eq!(report.removed, Vec::<String>::new());
+    }
+}
@@ -0,0 +1,240 @@
+//! Recording of the inputs a program receives and the output it produces, and
+//! replaying such a recording against a fresh runtime to check that the
+//! program behaves the same.
+//!
+//! A log has one entry per line, either `in <time> <id> <value>` for a value
+//! put on a node from outside, or `out <time> <value>` for a change of
+//! `stdout`. Inputs are logged under their names, which are their stable ids,
+//! and the results of effects under the stable id of the node asking for them.
+
+use crate::encoding::{decode_value, encode_value};
+use crate::program::VarType;
+use crate::run::{ListenMode, RuntimeEnv, Time};
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The line with the given number couldn't be parsed.
+    Malformed(usize),
+    /// The line with the given number refers to a node the runtime doesn't
+    /// have at that point.
+    UnknownInput(usize, String),
+    /// The inputs at the given time in the log were applied at another time.
+    TimeMismatch { logged: Time, replayed: Time },
+    /// The first output entry that differs between the log and the replay.
+    Diverged {
+        expected: Option<String>,
+        actual: Option<String>,
+    },
+}
+
+/// Log all inputs, effect results and output changes of the runtime from now
+/// on to `out`.
+pub fn start_recording(rte: &mut RuntimeEnv, out: Box<dyn Write>) {
+    let out = Rc::new(RefCell::new(out));
+
+    let input_out = out.clone();
+    rte.record_inputs(Box::new(move |t, id, value| {
+        let mut out = input_out.borrow_mut();
+        writeln!(out, "in {} {} {}", t, id, encode_value(value)).unwrap();
+        out.flush().unwrap();
+    }));
+
+    rte.listen(
+        rte.output("stdout").unwrap(),
+        true,
+        ListenMode::OnChange,
+        Box::new(move |t, value| {
+            let mut out = out.borrow_mut();
+            writeln!(out, "{}", output_entry(t, value)).unwrap();
+            out.flush().unwrap();
+        }),
+    )
+    .detach();
+}
+
+/// Drive a freshly built runtime with the inputs from a log, and check that it
+/// produces the same output. Returns the number of inputs replayed.
+pub fn replay(rte: &mut RuntimeEnv, log: &str) -> Result<usize, ReplayError> {
+    let mut inputs: Vec<(usize, Time, &str, VarType)> = Vec::new();
+    let mut expected: Vec<String> = Vec::new();
+
+    for (i, line) in log.lines().enumerate() {
+        let lineno = i + 1;
+        let mut parts = line.splitn(4, ' ');
+
+        match parts.next() {
+            Some("in") => {
+                let time = parts.next().and_then(|t| t.parse::<Time>().ok());
+                let id = parts.next();
+                let value = parts.next().and_then(decode_value);
+
+                match (time, id, value) {
+                    (Some(time), Some(id), Some(value)) => inputs.push((lineno, time, id, value)),
+                    _ => return Err(ReplayError::Malformed(lineno)),
+                }
+            }
+            Some("out") => expected.push(line.to_string()),
+            Some("") => (),
+            _ => return Err(ReplayError::Malformed(lineno)),
+        }
+    }
+
+    let actual = Rc::new(RefCell::new(Vec::new()));
+    let actual_cb = actual.clone();
+    let _handle = rte.listen(
+        rte.output("stdout").unwrap(),
+        true,
+        ListenMode::OnChange,
+        Box::new(move |t, value| actual_cb.borrow_mut().push(output_entry(t, value))),
+    );
+
+    // Inputs logged at the same time were applied in one transaction. The
+    // nodes of effects are looked up when they are due, since they may belong
+    // to instances created along the way.
+    let mut rest = &inputs[..];
+    while let Some((_, time, _, _)) = rest.first() {
+        let batch = rest.iter().take_while(|(_, t, _, _)| t == time).count();
+
+        let updates = rest[..batch]
+            .iter()
+            .map(|(lineno, _, id, value)| match rte.find_node(id) {
+                Some(idx) => Ok((idx, value.clone())),
+                None => Err(ReplayError::UnknownInput(*lineno, id.to_string())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let replayed = updates
+            .into_iter()
+            .fold(rte.begin(), |tx, (idx, value)| tx.set(idx, value))
+            .commit();
+
+        if replayed != *time {
+            return Err(ReplayError::TimeMismatch {
+                logged: *time,
+                replayed,
+            });
+        }
+
+        rest = &rest[batch..];
+    }
+
+    let actual = actual.borrow();
+    for i in 0..expected.len().max(actual.len()) {
+        if expected.get(i) != actual.get(i) {
+            return Err(ReplayError::Diverged {
+                expected: expected.get(i).cloned(),
+                actual: actual.get(i).cloned(),
+            });
+        }
+    }
+
+    Ok(inputs.len())
+}
+
+fn output_entry(t: Time, value: &VarType) -> String {
+    format!("out {} {}", t, encode_value(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::build_runtime;
+    use crate::effects::{start_requested, FakeEffects};
+    use crate::program::EffectKind;
+    use std::sync::mpsc;
+    use crate::nom_parse::parse_tempura;
+    use nom::error::VerboseError;
+
+    const SRC: &str = r#"mod main() {
+        if clock > 2 then stdin else "waiting"
+    }"#;
+
+    fn runtime() -> RuntimeEnv {
+        build_runtime(parse_tempura::<VerboseError<&str>>(SRC).unwrap().1).unwrap()
+    }
+
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn record() -> String {
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let mut rte = runtime();
+        start_recording(&mut rte, Box::new(SharedBuffer(buffer.clone())));
+
+        let (stdin, clock) = (rte.input("stdin").unwrap(), rte.input("clock").unwrap());
+        rte.put_current(stdin, VarType::from_string("first\\line\n"));
+        for t in 1..=4 {
+            rte.begin()
+                .set(clock, VarType::Int(t))
+                .set(stdin, VarType::from_string(&format!("line {}", t)))
+                .commit();
+        }
+
+        let log = String::from_utf8(buffer.borrow().clone()).unwrap();
+        log
+    }
+
+    #[test]
+    fn test_replay_reproduces_output() {
+        let log = record();
+        assert_eq!(replay(&mut runtime(), &log), Ok(9));
+    }
+
+    #[test]
+    fn test_replay_effect_results() {
+        let src = r#"mod main() {
+            name = if clock > 1 then "b.txt" else "a.txt"
+            "$name: ${read_file(name)}"
+        }"#;
+        let runtime = || build_runtime(parse_tempura::<VerboseError<&str>>(src).unwrap().1).unwrap();
+
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let mut rte = runtime();
+        start_recording(&mut rte, Box::new(SharedBuffer(buffer.clone())));
+
+        let mut fake = FakeEffects::new();
+        fake.set_result(EffectKind::ReadFile, vec!["a.txt".into()], Ok("apple".into()));
+        fake.set_result(EffectKind::ReadFile, vec!["b.txt".into()], Ok("banana".into()));
+        let (tx, rx) = mpsc::channel();
+        let deliver = move |result| tx.send(result).unwrap();
+        let clock = rte.input("clock").unwrap();
+
+        for t in 1..=3 {
+            rte.put_current(clock, VarType::Int(t));
+            start_requested(&mut rte, &mut fake, deliver.clone());
+            fake.finish_all();
+            for result in rx.try_iter() {
+                rte.complete(result);
+            }
+        }
+
+        let log = String::from_utf8(buffer.borrow().clone()).unwrap();
+        assert!(log.contains("str 13 b.txt: banana"));
+        assert_eq!(replay(&mut runtime(), &log), Ok(5));
+    }
+
+    #[test]
+    fn test_replay_detects_divergence() {
+        let log = record().replace("stdin str 6 line 4", "stdin str 5 other");
+
+        assert_eq!(
+            replay(&mut runtime(), &log),
+            Err(ReplayError::Diverged {
+                expected: Some("out 6 str 6 line 4".to_string()),
+                actual: Some("out 6 str 5 other".to_string()),
+            })
+        );
+    }
+}
@@ -1,8 +1,23 @@
 use crate::program::*;
 /// Contains code necessary to run a Tempura program in built form.
 use generational_arena::{Arena};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use crate::ast;
+use crate::effects::{EffectId, EffectRequest, EffectResult, EffectValue};
+use crate::encoding::encode_value;
+use crate::history::{History, HistoryLimit};
+use crate::memo::MemoCache;
+
+mod error;
+mod export;
+mod stable_hash;
+pub use error::RuntimeError;
+pub use export::GraphFormat;
+use stable_hash::StableHasher;
 
 
 pub struct Node {
@@ -10,42 +25,293 @@ pub struct Node {
     value_cache: Option<VarType>,
     operation: Operation<NodeIndex>,
     dependents: Vec<NodeIndex>,
-    listeners: Vec<Box<dyn Fn(Time, &VarType)>>,
+    listeners: Vec<Listener>,
     being_computed: bool,
+    /// For fragment applications, how the value is currently obtained.
+    application: Option<Application>,
+    /// Whether to stop propagation at this node when its recomputed value
+    /// equals the previous one.
+    suppress_unchanged: bool,
+    /// Past values, if history is being recorded for this node.
+    history: Option<History>,
+    /// Identifies the node across runs of the same program, see `stable_id`.
+    id: String,
+    /// Hash of the definition of the node, see `signature`.
+    signature: u64,
+    /// Name of the fragment this node was instantiated from, if any.
+    origin: Option<String>,
+}
+
+impl Node {
+    fn new(operation: Operation<NodeIndex>) -> Node {
+        Node {
+            value_cache: None,
+            operation,
+            dependents: Vec::new(),
+            listeners: Vec::new(),
+            being_computed: false,
+            last_update: 0,
+            application: None,
+            suppress_unchanged: true,
+            history: None,
+            id: String::new(),
+            signature: 0,
+            origin: None,
+        }
+    }
+
+    fn store(&mut self, time: Time, value: VarType) {
+        if let Some(history) = self.history.as_mut() {
+            history.record(time, &value);
+        }
+        self.value_cache = Some(value);
+        self.last_update = time;
+    }
+}
+
+/// Which updates of a node a listener is called for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ListenMode {
+    /// Only when the value of the node changed.
+    OnChange,
+    /// Every time the node is updated, even if the value stayed the same.
+    EveryUpdate,
+}
+
+struct Listener {
+    mode: ListenMode,
+    callback: Box<dyn FnMut(Time, &VarType)>,
+    active: Rc<Cell<bool>>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // Let the handle know the listener is gone, e.g. when its node was freed.
+        self.active.set(false);
+    }
+}
+
+/// Keeps a listener registered with `RuntimeEnv::listen` subscribed.
+/// Dropping or cancelling the handle unsubscribes the listener; call
+/// `detach` to keep it subscribed for the lifetime of the runtime.
+#[must_use = "dropping the handle unsubscribes the listener"]
+pub struct ListenerHandle {
+    active: Rc<Cell<bool>>,
+    detached: bool,
+}
+
+impl ListenerHandle {
+    pub fn cancel(self) {}
+
+    pub fn detach(mut self) {
+        self.detached = true;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.get()
+    }
+}
+
+impl Drop for ListenerHandle {
+    fn drop(&mut self) {
+        if !self.detached {
+            self.active.set(false);
+        }
+    }
+}
+
+/// A batch of updates to external nodes, see `RuntimeEnv::begin`.
+#[must_use = "a transaction does nothing unless committed"]
+pub struct Transaction<'a> {
+    rte: &'a mut RuntimeEnv,
+    updates: Vec<(NodeIndex, VarType)>,
+}
+
+impl<'a> Transaction<'a> {
+    /// Set the value of a node as of the commit. Setting the same node
+    /// twice keeps the last value.
+    pub fn set(mut self, idx: NodeIndex, value: VarType) -> Self {
+        self.updates.retain(|(i, _)| *i != idx);
+        self.updates.push((idx, value));
+        self
+    }
+
+    /// Set the next value of a node that takes a sequence of values, such as
+    /// lines of input. If the node was already set in this transaction, the
+    /// updates so far are applied first, so that no value is lost.
+    pub fn append(mut self, idx: NodeIndex, value: VarType) -> Self {
+        if self.updates.iter().any(|(i, _)| *i == idx) {
+            self.apply();
+        }
+        self.updates.push((idx, value));
+        self
+    }
+
+    /// Apply all updates at one new logical time and propagate them once.
+    pub fn commit(mut self) -> Time {
+        self.apply();
+        self.rte.current_time
+    }
+
+    fn apply(&mut self) {
+        let rte = &mut *self.rte;
+        rte.current_time += 1;
+
+        // Store every new value before propagating, so that nodes depending on
+        // several of the updated inputs only ever see the new values.
+        let mut changed = Vec::with_capacity(self.updates.len());
+        for (idx, value) in self.updates.drain(..) {
+            if let Some(t) = rte.input_types.get(&idx) {
+                if !value.has_type(t) {
+                    rte.errors.push(RuntimeError::InputType {
+                        time: rte.current_time,
+                        input: rte.nodes[idx.0].id.clone(),
+                        expected: t.clone(),
+                        value,
+                    });
+                    continue;
+                }
+            }
+
+            if let Some(record) = rte.input_recorder.as_mut() {
+                record(rte.current_time, &rte.nodes[idx.0].id, &value);
+            }
+
+            let node = &mut rte.nodes[idx.0];
+            changed.push((idx, node.value_cache.as_ref() != Some(&value)));
+            node.store(rte.current_time, value);
+        }
+
+        rte.propagate(changed);
+        rte.run_checks();
+    }
+}
+
+/// How a fragment application node obtains its value for the fragment it applies.
+enum Application {
+    /// A live instance of the fragment, kept up to date by propagation from
+    /// the arguments. The application node is subscribed to its output.
+    Instance(Rc<Fragment<LacunaryRef>>, FragmentInstance),
+    /// Values are looked up in the memo cache, or computed with a temporary
+    /// instance. The application node is subscribed to its arguments.
+    Memoized(Rc<Fragment<LacunaryRef>>),
+}
+
+impl Application {
+    fn fragment(&self) -> &Rc<Fragment<LacunaryRef>> {
+        match self {
+            Application::Instance(f, _) => f,
+            Application::Memoized(f) => f,
+        }
+    }
+}
+
+/// The nodes created by instantiating a fragment, and which of them is the output.
+struct FragmentInstance {
+    nodes: Vec<NodeIndex>,
+    output: NodeIndex,
+}
+
+/// Counters describing how much work the runtime has done.
+#[derive(Default, Debug, Clone)]
+pub struct RuntimeStats {
+    /// Number of times a node's value was (re)computed.
+    pub computations: u64,
+    /// Number of nodes created by instantiating fragments.
+    pub instantiated_nodes: u64,
+    /// Number of fragment applications answered from the memo cache.
+    pub memo_hits: u64,
 }
 
 pub struct RuntimeEnv {
     current_time: Time,
     nodes: Arena<Node>,
-    pub stdout: Option<NodeIndex>,
-    pub stdin: Option<NodeIndex>,
-    pub clock: Option<NodeIndex>,
+    stats: RuntimeStats,
+    memo: Option<MemoCache>,
+    /// History recording for nodes created from now on.
+    record_new_nodes: Option<HistoryLimit>,
+    /// Number of nodes created outside of fragment instances.
+    root_nodes: usize,
+    /// Called with every value put on a node from outside.
+    input_recorder: Option<InputRecorder>,
+    /// External nodes the host puts values on, by name.
+    inputs: BTreeMap<String, NodeIndex>,
+    /// Types of the inputs bound to parameters of the main module.
+    input_types: HashMap<NodeIndex, ast::Type>,
+    /// Nodes the host reads values from, by name.
+    outputs: BTreeMap<String, NodeIndex>,
+    /// Warnings about the program from when it was built.
+    pub warnings: Vec<String>,
+    /// Errors that occurred while running, not yet taken by `take_errors`.
+    errors: Vec<RuntimeError>,
+    /// Check nodes evaluated after every update, whether or not anything
+    /// reads them: those of invariants, and all those of the main module.
+    checks: Vec<NodeIndex>,
+    /// Effects asked for by the program, not yet taken by `take_requests`.
+    requests: Vec<EffectRequest>,
+    /// For each effect node, the request whose result it waits for or holds,
+    /// and the arguments of that request.
+    effects: HashMap<NodeIndex, (EffectId, Vec<VarType>)>,
+    /// Requests no longer wanted, not yet taken by `take_cancelled`.
+    cancelled: Vec<EffectId>,
+    /// Results of effects saved by an earlier run, by stable id of the effect
+    /// node, see `restore_effects`.
+    saved_effects: HashMap<String, VarType>,
+    /// Ticks counted before resuming, for requests of `every` that continue
+    /// a saved count.
+    tick_offsets: HashMap<EffectId, i64>,
 }
 
-type Time = u64;
+/// Ids of effect requests are unique across runtimes, so that results for a
+/// runtime that was replaced, e.g. by reloading, aren't taken for results of
+/// the new one.
+static NEXT_EFFECT: AtomicU64 = AtomicU64::new(0);
+
+pub type Time = u64;
+
+/// Called with the time, the stable id of the node and the value whenever a
+/// value is put on a node from outside.
+pub type InputRecorder = Box<dyn FnMut(Time, &str, &VarType)>;
+
+impl Default for RuntimeEnv {
+    fn default() -> RuntimeEnv {
+        RuntimeEnv::new()
+    }
+}
 
 impl RuntimeEnv {
     pub fn new() -> RuntimeEnv {
         RuntimeEnv {
             nodes: Arena::new(),
-            stdout: None,
-            stdin: None,
-            clock: None,
+            inputs: BTreeMap::new(),
+            input_types: HashMap::new(),
+            outputs: BTreeMap::new(),
+            warnings: Vec::new(),
+            errors: Vec::new(),
+            checks: Vec::new(),
+            requests: Vec::new(),
+            effects: HashMap::new(),
+            cancelled: Vec::new(),
+            saved_effects: HashMap::new(),
+            tick_offsets: HashMap::new(),
             current_time: 0,
+            stats: RuntimeStats::default(),
+            memo: None,
+            record_new_nodes: None,
+            root_nodes: 0,
+            input_recorder: None,
         }
     }
 
     pub fn node_from_operation(&mut self, operation: Operation<NodeIndex>) -> NodeIndex {
-        let dependencies = operation.dependencies();
+        let dependencies = propagation_sources(&operation);
 
-        let node = NodeIndex(self.nodes.insert(Node {
-            value_cache: None,
-            operation,
-            dependents: Vec::new(),
-            listeners: Vec::new(),
-            being_computed: false,
-            last_update: 0,
-        }));
+        let mut node = Node::new(operation);
+        node.history = self.record_new_nodes.map(History::new);
+        node.id = format!("#{}", self.root_nodes);
+        node.signature = self.signature_of(&node.operation, &node.id);
+        self.root_nodes += 1;
+        let node = NodeIndex(self.nodes.insert(node));
 
         for dep in dependencies {
             self.nodes[dep.0].dependents.push(node)
@@ -64,6 +330,7 @@ impl RuntimeEnv {
         }
 
         node.being_computed = true;
+        self.stats.computations += 1;
 
         let new_val = match node.operation.clone() {
             External => node.value_cache.clone().unwrap_or(VarType::Null),
@@ -75,47 +342,254 @@ impl RuntimeEnv {
                 let aa = self.pull_once(a);
                 let bb = self.pull_once(b);
 
+                // Null operands, e.g. a failed `parse_int`, give null.
                 match opr {
-                    ast::BinaryOp::Sum => VarType::Int(aa.unpack_int().unwrap() + bb.unpack_int().unwrap()),
-                    ast::BinaryOp::Concat => VarType::Vector(Rc::new(
-                        aa.unpack_vector().unwrap().iter().cloned()
-                            .chain(bb.unpack_vector().unwrap().iter().cloned()).collect(),
-                    )),
-                    ast::BinaryOp::Range => unimplemented!(),
-                    ast::BinaryOp::Eq => VarType::Bool(match aa {
-                        VarType::Bool(aa) => aa == bb.unpack_bool().unwrap(),
-                        VarType::Char(aa) => aa == bb.unpack_char().unwrap(),
-                        VarType::Int(aa) => aa == bb.unpack_int().unwrap(),
-                        _ => panic!("Comparison unsupported for vartype.")
-                    }),
-                    ast::BinaryOp::Gt  => VarType::Bool(aa.unpack_int().unwrap() > bb.unpack_int().unwrap()),
-                    ast::BinaryOp::Geq => VarType::Bool(aa.unpack_int().unwrap() >= bb.unpack_int().unwrap()),
-                    ast::BinaryOp::Lt  => VarType::Bool(aa.unpack_int().unwrap() < bb.unpack_int().unwrap()),
-                    ast::BinaryOp::Leq => VarType::Bool(aa.unpack_int().unwrap() <= bb.unpack_int().unwrap()),
-                    ast::BinaryOp::Index =>
-                        aa.unpack_vector().expect("can only index into a vector")[bb.unpack_int().expect("can only index with an int index") as usize].clone()
+                    ast::BinaryOp::Sum => match (aa.unpack_int(), bb.unpack_int()) {
+                        (Some(x), Some(y)) => x.checked_add(y).map(VarType::Int).unwrap_or(VarType::Null),
+                        _ => VarType::Null,
+                    },
+                    ast::BinaryOp::Concat => match (&aa, &bb) {
+                        (VarType::Str(sa), VarType::Str(sb)) => {
+                            VarType::Str(Rc::from([&sa[..], &sb[..]].concat()))
+                        }
+                        // A string concatenated with a vector counts as a vector of characters.
+                        _ => match (elements(&aa), elements(&bb)) {
+                            (Some(ea), Some(eb)) => VarType::Vector(Rc::new(ea.into_iter().chain(eb).collect())),
+                            _ => VarType::Null,
+                        },
+                    },
+                    // The integers from the first up to, but not including, the second.
+                    ast::BinaryOp::Range => match (aa.unpack_int(), bb.unpack_int()) {
+                        (Some(x), Some(y)) => VarType::Vector(Rc::new((x..y).map(VarType::Int).collect())),
+                        _ => VarType::Null,
+                    },
+                    ast::BinaryOp::Eq  => VarType::Bool(aa == bb),
+                    ast::BinaryOp::Gt  => VarType::Bool(aa > bb),
+                    ast::BinaryOp::Geq => VarType::Bool(aa >= bb),
+                    ast::BinaryOp::Lt  => VarType::Bool(aa < bb),
+                    ast::BinaryOp::Leq => VarType::Bool(aa <= bb),
+                    ast::BinaryOp::Index => {
+                        let (item, len) = match (&aa, bb.unpack_int()) {
+                            (VarType::Str(s), Some(i)) => (
+                                usize::try_from(i).ok().and_then(|i| s.chars().nth(i)).map(VarType::Char),
+                                s.chars().count(),
+                            ),
+                            (VarType::Vector(v), Some(i)) => {
+                                (usize::try_from(i).ok().and_then(|i| v.get(i).cloned()), v.len())
+                            }
+                            _ => (Some(VarType::Null), 0),
+                        };
+                        item.unwrap_or_else(|| {
+                            self.errors.push(RuntimeError::IndexOutOfRange {
+                                time: self.current_time,
+                                node: self.nodes[idx.0].id.clone(),
+                                index: bb.unpack_int().unwrap(),
+                                len,
+                            });
+                            VarType::Null
+                        })
+                    }
                 }
             },
             ToString(a) => VarType::from_string(&self.pull_once(a).render_as_string()),
-            IfElse(g, b, eb) => {
-                if self.pull_once(g).unpack_bool().unwrap() {
-                    self.pull_once(b)
-                } else {
-                    self.pull_once(eb)
+            Sort(a) => match self.pull_once(a).unpack_vector() {
+                Some(v) => {
+                    let mut sorted = (*v).clone();
+                    sorted.sort();
+                    VarType::Vector(Rc::new(sorted))
                 }
+                None => VarType::Null,
+            },
+            StringOp(op, args) => {
+                let values: Vec<VarType> = args.iter().map(|a| self.pull_once(*a)).collect();
+                apply_string_op(op, &values)
             }
-            ApplyFragment(fref, args) => {
-                let fragref = self.pull_once(fref).unpack_fragment().unwrap().clone();
-                let outref = self.instantiate_fragment(fragref.as_ref(), args);
-                self.pull_once(outref)
+            IfElse(g, b, eb) => match self.pull_once(g).unpack_bool() {
+                Some(true) => self.pull_once(b),
+                Some(false) => self.pull_once(eb),
+                // A null guard, e.g. a pending effect, gives null.
+                None => VarType::Null,
+            },
+            ApplyFragment(fref, args) => self.apply_fragment(idx, fref, args),
+            Native(f, args) => {
+                let values: Vec<VarType> = args.iter().map(|a| self.pull_once(*a)).collect();
+                match f.call(&values) {
+                    Ok(v) => v,
+                    // Keep the previous value, or start from the default of the
+                    // declared type, so the value keeps its type.
+                    Err(NativeError(message)) => {
+                        self.errors.push(RuntimeError::Native {
+                            time: self.current_time,
+                            node: self.nodes[idx.0].id.clone(),
+                            function: f.name.clone(),
+                            message,
+                        });
+                        match (&self.nodes[idx.0].value_cache, &f.signature.output) {
+                            (Some(v), _) => v.clone(),
+                            (None, Some(t)) => VarType::default_of(t),
+                            (None, None) => VarType::Null,
+                        }
+                    }
+                }
+            }
+            Effect(kind, args) => {
+                let values: Vec<VarType> = args.iter().map(|a| self.pull_once(*a)).collect();
+                match self.effects.get(&idx) {
+                    // Keep the result of the effect while its arguments stay the same.
+                    Some((_, requested)) if *requested == values => {
+                        self.nodes[idx.0].value_cache.clone().unwrap_or(VarType::Null)
+                    }
+                    _ => {
+                        let id = NEXT_EFFECT.fetch_add(1, Ordering::Relaxed);
+                        if let Some((old, _)) = self.effects.insert(idx, (id, values.clone())) {
+                            self.cancelled.push(old);
+                        }
+                        self.requests.push(EffectRequest {
+                            id,
+                            kind,
+                            args: values,
+                        });
+
+                        // A resumed effect keeps its saved result until the new
+                        // one arrives, and `every` keeps counting from there.
+                        match self.saved_effects.remove(&self.nodes[idx.0].id) {
+                            Some(saved) => {
+                                if let (EffectKind::Every, VarType::Int(ticks)) = (kind, &saved) {
+                                    self.tick_offsets.insert(id, *ticks);
+                                }
+                                saved
+                            }
+                            None => kind.pending_value(),
+                        }
+                    }
+                }
+            }
+            Check(claim, c, operands, output) => {
+                if self.pull_once(c) != VarType::Bool(true) {
+                    let values = claim
+                        .operands
+                        .iter()
+                        .cloned()
+                        .zip(operands.iter().map(|o| self.pull_once(*o)))
+                        .collect();
+                    self.errors.push(RuntimeError::ClaimFailed {
+                        time: self.current_time,
+                        node: self.nodes[idx.0].id.clone(),
+                        claim,
+                        values,
+                    });
+                }
+                self.pull_once(output)
             }
         };
-        self.nodes[idx.0].value_cache = Some(new_val.clone());
+        let time = self.current_time;
+        self.nodes[idx.0].store(time, new_val.clone());
         self.nodes[idx.0].being_computed = false;
-        self.nodes[idx.0].last_update = self.current_time;
         new_val
     }
 
+    fn apply_fragment(&mut self, idx: NodeIndex, fref: NodeIndex, args: Vec<NodeIndex>) -> VarType {
+        let fragref = self.pull_once(fref).unpack_fragment().unwrap().clone();
+
+        let same_fragment = match &self.nodes[idx.0].application {
+            Some(app) => Rc::ptr_eq(app.fragment(), &fragref),
+            None => false,
+        };
+
+        if !same_fragment {
+            if let Some(old) = self.nodes[idx.0].application.take() {
+                self.release_application(idx, &args, old);
+            }
+
+            let application = if self.memo.is_some() && fragref.memoize && self.is_pure(&fragref) {
+                for arg in args.iter() {
+                    self.nodes[arg.0].dependents.push(idx);
+                }
+                Application::Memoized(fragref)
+            } else {
+                // As long as the same fragment is applied, the instance is kept
+                // up to date by normal propagation from the arguments.
+                let prefix = self.nodes[idx.0].id.clone();
+                let instance = self.instantiate(&prefix, fragref.as_ref(), args.clone());
+                self.nodes[instance.output.0].dependents.push(idx);
+                Application::Instance(fragref, instance)
+            };
+
+            self.nodes[idx.0].application = Some(application);
+        }
+
+        match self.nodes[idx.0].application.as_ref().unwrap() {
+            Application::Instance(_, instance) => {
+                let outref = instance.output;
+                self.pull_once(outref)
+            }
+            Application::Memoized(fragment) => {
+                let fragment = fragment.clone();
+                let arg_values: Vec<VarType> = args.iter().map(|a| self.pull_once(*a)).collect();
+
+                if let Some(v) = self.memo.as_mut().unwrap().lookup(&fragment, &arg_values) {
+                    self.stats.memo_hits += 1;
+                    return v;
+                }
+
+                let prefix = self.nodes[idx.0].id.clone();
+                let instance = self.instantiate(&prefix, fragment.as_ref(), args);
+                let value = self.pull_once(instance.output);
+                self.free_instance(instance);
+
+                self.memo
+                    .as_mut()
+                    .unwrap()
+                    .insert(&fragment, arg_values, value.clone());
+                value
+            }
+        }
+    }
+
+    /// Enable memoization of applications of pure fragments, keeping at most
+    /// `capacity` results. Applications made before this keep their instances
+    /// until the fragment they apply changes.
+    pub fn enable_memoization(&mut self, capacity: usize) {
+        self.memo = Some(MemoCache::new(capacity));
+    }
+
+    /// A fragment is pure if the only values it refers to outside of itself and
+    /// its inputs are constants, so that its output depends only on its arguments,
+    /// and it doesn't ask for effects, or apply fragments that do.
+    fn is_pure(&self, frag: &Fragment<LacunaryRef>) -> bool {
+        let refs_constant = |refs: Vec<LacunaryRef>| {
+            refs.into_iter().all(|r| match r {
+                LacunaryRef::InstanciatedRef(n) => {
+                    self.is_constant(n)
+                        && match &self.nodes[n.0].operation {
+                            Operation::Const(VarType::Fragment(f)) => self.is_pure(f),
+                            _ => true,
+                        }
+                }
+                _ => true,
+            })
+        };
+
+        refs_constant(vec![frag.output])
+            && frag.nodes.iter().all(|op| {
+                refs_constant(op.dependencies())
+                    && match op {
+                        Operation::Const(VarType::Fragment(f)) => self.is_pure(f),
+                        Operation::Effect(_, _) => false,
+                        _ => true,
+                    }
+            })
+    }
+
+    /// Whether a node's value can never change.
+    fn is_constant(&self, idx: NodeIndex) -> bool {
+        match &self.nodes[idx.0].operation {
+            Operation::Const(_) => true,
+            Operation::External | Operation::ApplyFragment(_, _) | Operation::Effect(_, _) => false,
+            op => op.dependencies().into_iter().all(|d| self.is_constant(d)),
+        }
+    }
+
     pub fn pull_once(&mut self, idx: NodeIndex) -> VarType {
         let node = &mut self.nodes[idx.0];
 
@@ -129,33 +603,457 @@ impl RuntimeEnv {
         &mut self,
         idx: NodeIndex,
         include_current: bool,
-        cb: Box<dyn Fn(Time, &VarType)>,
-    ) {
+        mode: ListenMode,
+        mut cb: Box<dyn FnMut(Time, &VarType)>,
+    ) -> ListenerHandle {
         if include_current {
             cb(self.current_time, &self.pull_once(idx))
         }
-        self.nodes[idx.0].listeners.push(cb);
+
+        let active = Rc::new(Cell::new(true));
+
+        self.nodes[idx.0].listeners.push(Listener {
+            mode,
+            callback: cb,
+            active: active.clone(),
+        });
+
+        ListenerHandle {
+            active,
+            detached: false,
+        }
+    }
+
+    /// Choose whether propagation stops at a node when its value didn't
+    /// change (the default), or whether its dependents are recomputed every
+    /// time it is.
+    pub fn set_suppress_unchanged(&mut self, idx: NodeIndex, suppress: bool) {
+        self.nodes[idx.0].suppress_unchanged = suppress;
     }
 
     pub fn put_current(&mut self, idx: NodeIndex, value: VarType) -> Time {
-        self.current_time += 1;
-        self.nodes[idx.0].value_cache = Some(value);
-        self.nodes[idx.0].last_update = self.current_time;
-        self.update_dependents(idx);
-        self.current_time
+        self.begin().set(idx, value).commit()
     }
 
-    pub fn update_dependents(&mut self, idx: NodeIndex) {
-        for dep in self.nodes[idx.0].dependents.clone() {
-            if self.nodes[dep.0].last_update < self.current_time {
-                self.compute_value(dep);
-                self.update_dependents(dep);
+    /// Start a transaction: the values set on it are applied together at a
+    /// single logical time when it is committed, or discarded if it is
+    /// dropped instead.
+    pub fn begin(&mut self) -> Transaction<'_> {
+        Transaction {
+            rte: self,
+            updates: Vec::new(),
+        }
+    }
+
+    /// Recompute everything depending on the updated nodes, given with whether
+    /// their value changed. All dependents are collected first and recomputed
+    /// in topological order, so that each is computed at most once and only
+    /// after all of its sources. Propagation stops at nodes whose value didn't
+    /// change and that suppress unchanged values.
+    fn propagate(&mut self, updated: Vec<(NodeIndex, bool)>) {
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        for (idx, _) in updated.iter() {
+            self.collect_dependents(*idx, &mut visited, &mut order);
+        }
+
+        let updated: HashMap<NodeIndex, bool> = updated.into_iter().collect();
+        let mut triggered = HashSet::new();
+
+        for idx in order.into_iter().rev() {
+            // Dependents may have been freed by an earlier re-application.
+            if !self.nodes.contains(idx.0) {
+                continue;
             }
+
+            let changed = match updated.get(&idx) {
+                Some(changed) => *changed,
+                None if !triggered.contains(&idx) => continue,
+                // Already computed at this time, from scratch, by a dependent
+                // pulling it.
+                None if self.nodes[idx.0].last_update == self.current_time => true,
+                None => {
+                    let old = self.nodes[idx.0].value_cache.clone();
+                    let new = self.compute_value(idx);
+                    old.as_ref() != Some(&new)
+                }
+            };
+
+            if changed || !self.nodes[idx.0].suppress_unchanged {
+                triggered.extend(self.nodes[idx.0].dependents.iter().cloned());
+            }
+            self.notify_listeners(idx, changed);
+        }
+    }
+
+    /// Add a node and everything depending on it to `order`, each node after
+    /// all of its dependents.
+    fn collect_dependents(&self, idx: NodeIndex, visited: &mut HashSet<NodeIndex>, order: &mut Vec<NodeIndex>) {
+        if !visited.insert(idx) {
+            return;
         }
+
+        for dep in self.nodes[idx.0].dependents.iter() {
+            if self.nodes.contains(dep.0) {
+                self.collect_dependents(*dep, visited, order);
+            }
+        }
+        order.push(idx);
+    }
+
+    fn notify_listeners(&mut self, idx: NodeIndex, changed: bool) {
         let cur = self.pull_once(idx);
-        for cb in self.nodes[idx.0].listeners.iter() {
-            cb(self.current_time, &cur);
+        let time = self.current_time;
+        let listeners = &mut self.nodes[idx.0].listeners;
+
+        listeners.retain(|l| l.active.get());
+
+        for l in listeners.iter_mut() {
+            if changed || l.mode == ListenMode::EveryUpdate {
+                (l.callback)(time, &cur);
+            }
+        }
+    }
+
+    /// Evaluate the check nodes that don't wait to be read, forgetting those
+    /// of instances that were freed.
+    fn run_checks(&mut self) {
+        let nodes = &self.nodes;
+        self.checks.retain(|idx| nodes.contains(idx.0));
+        for idx in self.checks.clone() {
+            self.pull_once(idx);
+        }
+    }
+
+    /// Take the errors that occurred since the last call.
+    pub fn take_errors(&mut self) -> Vec<RuntimeError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Take the effects asked for since the last call, to be performed by the host.
+    pub fn take_requests(&mut self) -> Vec<EffectRequest> {
+        std::mem::take(&mut self.requests)
+    }
+
+    /// Take the requests that are no longer wanted since the last call,
+    /// because their arguments changed or their nodes were freed.
+    pub fn take_cancelled(&mut self) -> Vec<EffectId> {
+        std::mem::take(&mut self.cancelled)
+    }
+
+    /// Cancel all requests, such as before replacing the runtime. Requests
+    /// not yet taken are dropped, and the others are to be cancelled.
+    pub fn cancel_effects(&mut self) {
+        let queued: HashSet<EffectId> = self.requests.drain(..).map(|r| r.id).collect();
+        let ids = self.effects.drain().map(|(_, (id, _))| id);
+        self.cancelled.extend(ids.filter(|id| !queued.contains(id)));
+    }
+
+    /// Deliver the result of an effect, putting it on the node that asked for
+    /// it. Results of requests that are no longer wanted are ignored.
+    /// Failures, e.g. when a watched file disappears, are reported as errors
+    /// and put the pending value back. Returns the time of the update, if any.
+    pub fn complete(&mut self, result: EffectResult) -> Option<Time> {
+        let idx = self
+            .effects
+            .iter()
+            .find(|(_, (id, _))| *id == result.id)
+            .map(|(idx, _)| *idx)?;
+
+        let kind = match &self.nodes[idx.0].operation {
+            Operation::Effect(kind, _) => *kind,
+            _ => unreachable!(),
+        };
+
+        match result.value {
+            Ok(EffectValue::Int(ticks)) if self.tick_offsets.contains_key(&result.id) => {
+                Some(self.put_current(idx, VarType::Int(self.tick_offsets[&result.id] + ticks)))
+            }
+            Ok(value) => Some(self.put_current(idx, value.into())),
+            Err(message) => {
+                let time = self.put_current(idx, kind.pending_value());
+                self.errors.push(RuntimeError::Effect {
+                    time,
+                    node: self.nodes[idx.0].id.clone(),
+                    effect: kind.name().to_string(),
+                    message,
+                });
+                Some(time)
+            }
+        }
+    }
+
+    /// Have every value put on a node from outside, including the results of
+    /// effects, passed to `recorder` with the stable id of the node and the
+    /// time at which it is applied.
+    pub fn record_inputs(&mut self, recorder: InputRecorder) {
+        self.input_recorder = Some(recorder);
+    }
+
+    pub fn take_input_recorder(&mut self) -> Option<InputRecorder> {
+        self.input_recorder.take()
+    }
+
+    /// Move the listeners, history and propagation setting of a node of
+    /// another runtime onto a node of this one, such as when replacing a
+    /// runtime with a rebuilt one.
+    pub fn adopt_node_state(&mut self, idx: NodeIndex, other: &mut RuntimeEnv, other_idx: NodeIndex) {
+        let listeners = std::mem::take(&mut other.nodes[other_idx.0].listeners);
+        self.nodes[idx.0].listeners.extend(listeners);
+        self.nodes[idx.0].suppress_unchanged = other.nodes[other_idx.0].suppress_unchanged;
+
+        if let Some(history) = other.nodes[other_idx.0].history.take() {
+            self.nodes[idx.0].history = Some(history);
+        }
+    }
+
+    /// Start recording the values of a node, so they can be looked up with `value_at`.
+    pub fn record_history(&mut self, idx: NodeIndex, limit: HistoryLimit) {
+        let node = &mut self.nodes[idx.0];
+        let mut history = History::new(limit);
+        if let Some(v) = &node.value_cache {
+            history.record(node.last_update, v);
+        }
+        node.history = Some(history);
+    }
+
+    /// Record the history of every node, including those created later on.
+    pub fn record_all_history(&mut self, limit: HistoryLimit) {
+        let indices: Vec<NodeIndex> = self.node_indices().collect();
+        for idx in indices {
+            self.record_history(idx, limit);
+        }
+        self.record_new_nodes = Some(limit);
+    }
+
+    /// The value a node had at the given time, if its history reaches back that far.
+    pub fn value_at(&self, idx: NodeIndex, time: Time) -> Option<VarType> {
+        self.nodes
+            .get(idx.0)?
+            .history
+            .as_ref()?
+            .value_at(time)
+            .cloned()
+    }
+
+    /// A name for the node that is the same every time the same program is
+    /// built and run. Nodes of fragment instances are named after the node
+    /// applying the fragment, followed by the name of the value in the source,
+    /// or the signature for unnamed ones. Other nodes are numbered in order of
+    /// creation unless given a name with `set_stable_id`.
+    pub fn stable_id(&self, idx: NodeIndex) -> &str {
+        &self.nodes[idx.0].id
+    }
+
+    pub fn set_stable_id(&mut self, idx: NodeIndex, id: &str) {
+        self.nodes[idx.0].id = id.to_string();
+
+        // External nodes are told apart by name only.
+        if let Operation::External = self.nodes[idx.0].operation {
+            self.nodes[idx.0].signature = self.signature_of(&Operation::External, id);
+        }
+    }
+
+    /// A hash of the definition of a node: its operation and the signatures
+    /// of the nodes it depends on. Nodes with the same signature in two builds
+    /// of a program compute the same value.
+    pub fn signature(&self, idx: NodeIndex) -> u64 {
+        self.nodes[idx.0].signature
+    }
+
+    /// The signature of a node, from an explicit encoding of its operation
+    /// hashed with `StableHasher`, so that it doesn't change between builds of
+    /// tempura. Fragments are told apart by name: changes to their definition
+    /// show in the signatures of the nodes of their instances.
+    fn signature_of(&self, operation: &Operation<NodeIndex>, id: &str) -> u64 {
+        use Operation::*;
+
+        let mut hasher = StableHasher::new();
+
+        match operation {
+            External => {
+                hasher.write_str("external");
+                hasher.write_str(id);
+            }
+            Const(v) => {
+                hasher.write_str("const");
+                hasher.write_str(&encode_value(v));
+            }
+            Vector(_) => hasher.write_str("vector"),
+            BinaryOp(_, _, op) => {
+                hasher.write_str("binary");
+                hasher.write_str(match op {
+                    ast::BinaryOp::Sum => "sum",
+                    ast::BinaryOp::Geq => "geq",
+                    ast::BinaryOp::Leq => "leq",
+                    ast::BinaryOp::Eq => "eq",
+                    ast::BinaryOp::Lt => "lt",
+                    ast::BinaryOp::Gt => "gt",
+                    ast::BinaryOp::Concat => "concat",
+                    ast::BinaryOp::Index => "index",
+                    ast::BinaryOp::Range => "range",
+                });
+            }
+            ToString(_) => hasher.write_str("to_string"),
+            Sort(_) => hasher.write_str("sort"),
+            StringOp(op, _) => {
+                hasher.write_str("string_op");
+                hasher.write_str(op.name());
+            }
+            IfElse(_, _, _) => hasher.write_str("if"),
+            ApplyFragment(_, _) => hasher.write_str("apply"),
+            Check(claim, _, _, _) => {
+                hasher.write_str("check");
+                hasher.write_str(claim.kind.keyword());
+                hasher.write_str(&claim.source);
+                hasher.write_u64(claim.operands.len() as u64);
+                for operand in claim.operands.iter() {
+                    hasher.write_str(operand);
+                }
+            }
+            Native(f, _) => {
+                hasher.write_str("native");
+                hasher.write_str(&f.name);
+            }
+            Effect(kind, _) => {
+                hasher.write_str("effect");
+                hasher.write_str(kind.name());
+            }
+        }
+
+        let dependencies = operation.dependencies();
+        hasher.write_u64(dependencies.len() as u64);
+        for dep in dependencies {
+            hasher.write_u64(self.nodes[dep.0].signature);
+        }
+
+        hasher.finish()
+    }
+
+    pub fn find_node(&self, id: &str) -> Option<NodeIndex> {
+        self.nodes
+            .iter()
+            .find(|(_, n)| n.id == id)
+            .map(|(i, _)| NodeIndex(i))
+    }
+
+    /// The cached value of a node and the time it was computed, if any.
+    pub fn cached_value(&self, idx: NodeIndex) -> Option<(Time, &VarType)> {
+        let node = &self.nodes[idx.0];
+        node.value_cache.as_ref().map(|v| (node.last_update, v))
+    }
+
+    /// Restore the state of a runtime that was saved at `time`, given the
+    /// values of its external nodes. Nodes computed from those are recomputed
+    /// when next needed.
+    pub fn restore(&mut self, time: Time, externals: Vec<(NodeIndex, Time, VarType)>) {
+        for (_, node) in self.nodes.iter_mut() {
+            if node.operation != Operation::External {
+                node.value_cache = None;
+            }
         }
+
+        self.current_time = time;
+
+        for (idx, t, value) in externals {
+            self.nodes[idx.0].store(t, value);
+        }
+    }
+
+    /// Have effect nodes that ask for their effect for the first time start
+    /// from the result a previous run saved, by stable id, instead of the
+    /// pending value.
+    pub fn restore_effects(&mut self, results: HashMap<String, VarType>) {
+        self.saved_effects = results;
+    }
+
+    pub fn node_indices(&self) -> impl Iterator<Item = NodeIndex> + '_ {
+        self.nodes.iter().map(|(i, _)| NodeIndex(i))
+    }
+
+    pub fn operation(&self, idx: NodeIndex) -> &Operation<NodeIndex> {
+        &self.nodes[idx.0].operation
+    }
+
+    pub fn current_time(&self) -> Time {
+        self.current_time
+    }
+
+    /// Number of nodes currently alive in the runtime graph.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn stats(&self) -> &RuntimeStats {
+        &self.stats
+    }
+
+    /// Create an external node that the host puts values on under `name`,
+    /// which is also its stable id.
+    pub fn declare_input(&mut self, name: &str) -> NodeIndex {
+        let idx = self.node_from_operation(Operation::External);
+        self.set_stable_id(idx, name);
+        self.inputs.insert(name.to_string(), idx);
+        idx
+    }
+
+    /// Give an input a type. Values of other types put on it are reported as
+    /// errors and otherwise ignored, and until a value is put on it, it has
+    /// the default value of its type.
+    pub fn set_input_type(&mut self, idx: NodeIndex, t: ast::Type) {
+        let node = &mut self.nodes[idx.0];
+        if node.value_cache.as_ref().map_or(true, |v| !v.has_type(&t)) {
+            node.value_cache = Some(VarType::default_of(&t));
+        }
+        self.input_types.insert(idx, t);
+    }
+
+    pub fn input_type(&self, idx: NodeIndex) -> Option<&ast::Type> {
+        self.input_types.get(&idx)
+    }
+
+    pub fn input(&self, name: &str) -> Option<NodeIndex> {
+        self.inputs.get(name).copied()
+    }
+
+    pub fn inputs(&self) -> impl Iterator<Item = (&str, NodeIndex)> + '_ {
+        self.inputs.iter().map(|(name, idx)| (&name[..], *idx))
+    }
+
+    pub fn declare_output(&mut self, name: &str, idx: NodeIndex) {
+        self.outputs.insert(name.to_string(), idx);
+    }
+
+    pub fn output(&self, name: &str) -> Option<NodeIndex> {
+        self.outputs.get(name).copied()
+    }
+
+    pub fn outputs(&self) -> impl Iterator<Item = (&str, NodeIndex)> + '_ {
+        self.outputs.iter().map(|(name, idx)| (&name[..], *idx))
+    }
+
+    /// Instantiate the main module of a program, declaring its result as the
+    /// `stdout` output and its `out` values as outputs under their names.
+    /// Its asserts are checked after every update like invariants, as hosts
+    /// may only read some of its outputs.
+    pub fn instantiate_main(&mut self, frag: &Fragment<LacunaryRef>, arguments: Vec<NodeIndex>) -> NodeIndex {
+        let instance = self.instantiate(&frag.name, frag, arguments.clone());
+
+        for idx in instance.nodes.iter() {
+            if let Operation::Check(claim, ..) = &self.nodes[idx.0].operation {
+                if claim.kind == ast::ClaimKind::Assert {
+                    self.checks.push(*idx);
+                }
+            }
+        }
+
+        for (name, lr) in frag.outputs.iter() {
+            let idx = lr.fill_in(&instance.nodes, &arguments, 0).finalize();
+            self.declare_output(name, idx);
+        }
+        self.declare_output("stdout", instance.output);
+
+        instance.output
     }
 
     pub fn instantiate_fragment(
@@ -163,6 +1061,58 @@ impl RuntimeEnv {
         frag: &Fragment<LacunaryRef>,
         arguments: Vec<NodeIndex>,
     ) -> NodeIndex {
+        self.instantiate(&frag.name, frag, arguments).output
+    }
+
+    /// Unsubscribe an application node from the nodes its current application
+    /// depends on, and free the instance if it has one.
+    fn release_application(&mut self, owner: NodeIndex, args: &[NodeIndex], application: Application) {
+        let subscribed_to = match &application {
+            Application::Instance(_, instance) => vec![instance.output],
+            Application::Memoized(_) => args.to_vec(),
+        };
+
+        for source in subscribed_to {
+            if let Some(node) = self.nodes.get_mut(source.0) {
+                node.dependents.retain(|d| *d != owner);
+            }
+        }
+
+        if let Application::Instance(_, instance) = application {
+            self.free_instance(instance);
+        }
+    }
+
+    /// Remove the nodes of an instance from the graph, along with any instances
+    /// they own, and unregister them as dependents of the nodes they depended on.
+    fn free_instance(&mut self, instance: FragmentInstance) {
+        for idx in instance.nodes.iter() {
+            if let Some((id, _)) = self.effects.remove(idx) {
+                self.cancelled.push(id);
+            }
+
+            if let Some(node) = self.nodes.remove(idx.0) {
+                if let (Some(sub), Operation::ApplyFragment(_, args)) = (node.application, &node.operation) {
+                    self.release_application(*idx, args, sub);
+                }
+
+                for dep in propagation_sources(&node.operation) {
+                    if let Some(dep_node) = self.nodes.get_mut(dep.0) {
+                        dep_node.dependents.retain(|d| d != idx);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Create the nodes of a fragment, identified by `prefix` followed by
+    /// their name or signature.
+    fn instantiate(
+        &mut self,
+        prefix: &str,
+        frag: &Fragment<LacunaryRef>,
+        arguments: Vec<NodeIndex>,
+    ) -> FragmentInstance {
         let indices = self.nodes.insert_many_with(frag.nodes.len(), |indices| {
             let noderefs: Vec<NodeIndex> = indices.iter().cloned().map(NodeIndex).collect();
 
@@ -170,27 +1120,656 @@ impl RuntimeEnv {
                 .finalize()
                 .nodes
                 .into_iter()
-                .map(|op| Node {
-                    value_cache: None,
-                    being_computed: false,
-                    operation: op,
-                    dependents: vec![],
-                    listeners: vec![],
-                    last_update: 0,
-                })
+                .map(Node::new)
                 .collect()
         });
 
-        for idx in indices.iter() {
-            for dep in self.nodes[*idx].operation.dependencies() {
+        let mut ids = HashSet::new();
+
+        for (i, idx) in indices.iter().enumerate() {
+            self.nodes[*idx].history = self.record_new_nodes.map(History::new);
+
+            let signature = self.signature_of(&self.nodes[*idx].operation, "");
+            let base = match frag.names.get(&i) {
+                Some(name) => format!("{}/{}", prefix, name),
+                None => format!("{}/{:016x}", prefix, signature),
+            };
+            // Unnamed nodes with the same definition are interchangeable, so
+            // it doesn't matter which gets which suffix.
+            let mut id = base.clone();
+            let mut n = 1;
+            while !ids.insert(id.clone()) {
+                n += 1;
+                id = format!("{}~{}", base, n);
+            }
+            self.nodes[*idx].id = id;
+            self.nodes[*idx].signature = signature;
+            self.nodes[*idx].origin = Some(frag.name.clone());
+
+            for dep in propagation_sources(&self.nodes[*idx].operation) {
                 self.nodes[dep.0].dependents.push(NodeIndex(*idx))
             }
+
+            if let Operation::Check(claim, ..) = &self.nodes[*idx].operation {
+                if claim.kind == ast::ClaimKind::Invariant {
+                    self.checks.push(NodeIndex(*idx));
+                }
+            }
         }
 
         let noderefs: Vec<NodeIndex> = indices.iter().cloned().map(NodeIndex).collect();
 
-        frag.output
+        let output = frag.output
             .fill_in(noderefs.as_slice(), arguments.as_slice(), 0)
-            .finalize()
+            .finalize();
+
+        self.stats.instantiated_nodes += noderefs.len() as u64;
+
+        FragmentInstance {
+            nodes: noderefs,
+            output,
+        }
+    }
+}
+
+/// The nodes whose updates should cause a node to be recomputed.
+///
+/// A fragment application only depends on the fragment being applied: its
+/// arguments feed into the instance, and the application is subscribed to the
+/// output of the instance once it exists.
+fn propagation_sources(operation: &Operation<NodeIndex>) -> Vec<NodeIndex> {
+    match operation {
+        Operation::ApplyFragment(f, _) => vec![*f],
+        op => op.dependencies(),
+    }
+}
+
+/// The elements of a vector, or the characters of a string.
+fn elements(v: &VarType) -> Option<Vec<VarType>> {
+    match v {
+        VarType::Vector(items) => Some(items.as_ref().clone()),
+        VarType::Str(s) => Some(s.chars().map(VarType::Char).collect()),
+        _ => None,
+    }
+}
+
+/// Apply a string operation, giving null for arguments of the wrong type,
+/// such as a null from a failed `parse_int`.
+fn apply_string_op(op: StringOp, args: &[VarType]) -> VarType {
+    string_op(op, args).unwrap_or(VarType::Null)
+}
+
+/// Evaluate a primitive string operation on already-computed arguments.
+fn string_op(op: StringOp, args: &[VarType]) -> Option<VarType> {
+    let string = |i: usize| args[i].unpack_str();
+    let int = |i: usize| args[i].unpack_int();
+
+    Some(match op {
+        StringOp::Split => {
+            let (s, sep) = (string(0)?, string(1)?);
+            let parts: Vec<VarType> = if sep.is_empty() {
+                s.chars().map(|c| VarType::from_string(&c.to_string())).collect()
+            } else {
+                s.split(&sep[..]).map(VarType::from_string).collect()
+            };
+            VarType::Vector(Rc::new(parts))
+        }
+        StringOp::Trim => VarType::from_string(string(0)?.trim()),
+        StringOp::Substr => {
+            let start = int(1)?.max(0) as usize;
+            let len = int(2)?.max(0) as usize;
+            VarType::from_string(&string(0)?.chars().skip(start).take(len).collect::<String>())
+        }
+        StringOp::Find => {
+            let (s, needle) = (string(0)?, string(1)?);
+            VarType::Int(match s.find(&needle[..]) {
+                Some(byte_idx) => s[..byte_idx].chars().count() as i64,
+                None => -1,
+            })
+        }
+        StringOp::Replace => {
+            VarType::from_string(&string(0)?.replace(&string(1)?[..], &string(2)?))
+        }
+        StringOp::ToUpper => VarType::from_string(&string(0)?.to_uppercase()),
+        StringOp::ParseInt => match string(0)?.trim().parse::<i64>() {
+            Ok(i) => VarType::Int(i),
+            Err(_) => VarType::Null,
+        },
+        StringOp::Len => match &args[0] {
+            VarType::Vector(v) => VarType::Int(v.len() as i64),
+            _ => VarType::Int(string(0)?.chars().count() as i64),
+        },
+        StringOp::Get => {
+            let element = usize::try_from(int(1)?).ok().and_then(|i| match &args[0] {
+                VarType::Vector(v) => v.get(i).cloned(),
+                _ => string(0)?.chars().nth(i).map(VarType::Char),
+            });
+            element.unwrap_or_else(|| args[2].clone())
+        }
+        StringOp::Clamp => VarType::Int(int(0)?.max(int(1)?).min(int(2)?)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::build_runtime;
+    use crate::nom_parse::parse_tempura;
+    use nom::error::VerboseError;
+
+    fn runtime(src: &str) -> RuntimeEnv {
+        build_runtime(parse_tempura::<VerboseError<&str>>(src).unwrap().1).unwrap()
+    }
+
+    #[test]
+    fn test_reapplication_frees_instances() {
+        let mut rte = runtime(
+            r#"mod main() {
+                mod double(x : int) {
+                    x + x
+                }
+                mod increment(x : int) {
+                    x + 1
+                }
+                out other = increment(stdin)
+                double(stdin)
+            }"#,
+        );
+
+        let stdout = rte.output("stdout").unwrap();
+        let stdin = rte.input("stdin").unwrap();
+
+        // Programs can't choose a fragment at runtime yet, so drive the
+        // fragment applied for stdout by hand.
+        let fragment = |rte: &RuntimeEnv, name: &str| {
+            rte.nodes
+                .iter()
+                .find_map(|(i, n)| match &n.operation {
+                    Operation::Const(VarType::Fragment(f)) if f.name == name => Some((NodeIndex(i), f.clone())),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let (chosen, double) = fragment(&rte, "double");
+        let (_, increment) = fragment(&rte, "increment");
+        rte.nodes[chosen.0].operation = Operation::External;
+
+        rte.begin()
+            .set(chosen, VarType::Fragment(double.clone()))
+            .set(stdin, VarType::Int(10))
+            .commit();
+        assert_eq!(rte.pull_once(stdout).unpack_int(), Some(20));
+        let initial = rte.node_count();
+        let initial_dependents = rte.nodes[stdin.0].dependents.len();
+
+        // Each tick applies the other fragment, freeing the previous instance.
+        for t in 1..=1_000 {
+            let (frag, expected) = if t % 2 == 1 { (&increment, 11) } else { (&double, 20) };
+            rte.put_current(chosen, VarType::Fragment(frag.clone()));
+            assert_eq!(rte.pull_once(stdout).unpack_int(), Some(expected));
+            assert!(rte.node_count() <= initial + 1);
+
+            let dependents = &rte.nodes[stdin.0].dependents;
+            assert!(dependents.len() <= initial_dependents);
+            assert!(dependents.iter().all(|d| rte.nodes.get(d.0).is_some()));
+        }
+    }
+
+    /// Source of a program applying a fragment with `size` nodes that don't
+    /// depend on the fragment's input.
+    fn large_fragment_program(size: usize) -> String {
+        let body: Vec<String> = (1..size)
+            .map(|i| format!("a{} = a{} + 1", i, i - 1))
+            .collect();
+
+        format!(
+            "mod main() {{\nmod big(x : int) {{\na0 = 0\n{}\nx + a{}\n}}\nbig(clock)\n}}",
+            body.join("\n"),
+            size - 1
+        )
+    }
+
+    /// Per-tick work for a program applying a fragment of the given size.
+    fn work_per_tick(size: usize) -> (u64, u64) {
+        let mut rte = runtime(&large_fragment_program(size));
+        let stdout = rte.output("stdout").unwrap();
+        let clock = rte.input("clock").unwrap();
+
+        rte.pull_once(stdout);
+        let before = rte.stats().clone();
+
+        let ticks = 100;
+        for t in 1..=ticks {
+            rte.put_current(clock, VarType::Int(t));
+        }
+
+        assert_eq!(rte.pull_once(stdout).unpack_int(), Some(ticks + size as i64 - 1));
+
+        let after = rte.stats();
+        (
+            (after.computations - before.computations) / ticks as u64,
+            (after.instantiated_nodes - before.instantiated_nodes) / ticks as u64,
+        )
+    }
+
+    /// Count how often a listener on the output of the program is called over 10 ticks.
+    fn count_notifications(src: &str, suppress: bool, mode: ListenMode) -> usize {
+        let mut rte = runtime(src);
+        let stdout = rte.output("stdout").unwrap();
+        let clock = rte.input("clock").unwrap();
+
+        rte.set_suppress_unchanged(stdout, suppress);
+        rte.pull_once(stdout);
+
+        let count = Rc::new(Cell::new(0));
+        let count_cb = count.clone();
+        let _handle = rte.listen(stdout, false, mode, Box::new(move |_, _| count_cb.set(count_cb.get() + 1)));
+
+        for t in 1..=10 {
+            rte.put_current(clock, VarType::Int(t));
+        }
+
+        count.get()
+    }
+
+    #[test]
+    fn test_unchanged_values_not_propagated() {
+        let src = r#"mod main() {
+            clock > 5
+        }"#;
+
+        assert_eq!(count_notifications(src, true, ListenMode::OnChange), 1);
+        assert_eq!(count_notifications(src, true, ListenMode::EveryUpdate), 10);
+        assert_eq!(count_notifications(src, false, ListenMode::EveryUpdate), 10);
+
+        // The if-else isn't recomputed while the guard stays the same.
+        let src = r#"mod main() {
+            if clock > 5 then "big" else "small"
+        }"#;
+
+        assert_eq!(count_notifications(src, false, ListenMode::EveryUpdate), 1);
+    }
+
+    #[test]
+    fn test_transaction_propagates_once() {
+        let mut rte = runtime(
+            r#"mod main() {
+                clock + stdin
+            }"#,
+        );
+        let stdout = rte.output("stdout").unwrap();
+        let (clock, stdin) = (rte.input("clock").unwrap(), rte.input("stdin").unwrap());
+
+        rte.put_current(stdin, VarType::Int(0));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let _handle = rte.listen(
+            stdout,
+            false,
+            ListenMode::EveryUpdate,
+            Box::new(move |t, v| tx.send((t, v.clone())).unwrap()),
+        );
+
+        let before = rte.current_time;
+        let t = rte
+            .begin()
+            .set(clock, VarType::Int(1))
+            .set(stdin, VarType::Int(5))
+            .set(stdin, VarType::Int(10))
+            .commit();
+
+        assert_eq!(t, before + 1);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![(t, VarType::Int(11))]);
+
+        // Dropping a transaction discards its updates.
+        drop(rte.begin().set(clock, VarType::Int(100)));
+
+        assert_eq!(rte.current_time, t);
+        assert_eq!(rte.pull_once(stdout), VarType::Int(11));
+    }
+
+    #[test]
+    fn test_propagation_is_atomic() {
+        // Clock reaches the output in one step through a, and in two through
+        // b and c.
+        let mut rte = runtime(
+            r#"mod main() {
+                a = clock + 1
+                b = clock + 2
+                c = stdin + b
+                a + c
+            }"#,
+        );
+        let stdout = rte.output("stdout").unwrap();
+        let (clock, stdin) = (rte.input("clock").unwrap(), rte.input("stdin").unwrap());
+
+        rte.begin().set(clock, VarType::Int(0)).set(stdin, VarType::Int(0)).commit();
+        assert_eq!(rte.pull_once(stdout), VarType::Int(3));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let _handle = rte.listen(
+            stdout,
+            false,
+            ListenMode::EveryUpdate,
+            Box::new(move |_, v| tx.send(v.clone()).unwrap()),
+        );
+
+        let computations = rte.stats().computations;
+        rte.put_current(clock, VarType::Int(1));
+        rte.begin().set(clock, VarType::Int(2)).set(stdin, VarType::Int(10)).commit();
+
+        // The output is only ever computed from consistent values, once per update.
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![VarType::Int(5), VarType::Int(17)]);
+        assert_eq!(rte.pull_once(stdout), VarType::Int(17));
+        assert_eq!(rte.stats().computations - computations, 8);
+    }
+
+    #[test]
+    fn test_stable_ids() {
+        let rte = runtime(
+            r#"mod main() {
+                total = clock + 100
+                total + 1
+            }"#,
+        );
+
+        // Ids of unnamed nodes are saved in replay logs and snapshots, so they
+        // must not change unless the definitions of the nodes do.
+        let mut ids: Vec<&str> = rte
+            .node_indices()
+            .map(|idx| rte.stable_id(idx))
+            .filter(|id| id.starts_with("main/"))
+            .collect();
+        ids.sort();
+        assert_eq!(
+            ids,
+            vec!["main/2e4e3569a3538bc2", "main/dcdbccb91090ae6c", "main/ec9c9410d5751f73", "main/total"]
+        );
+    }
+
+    #[test]
+    fn test_value_at() {
+        let mut rte = runtime(
+            r#"mod main() {
+                if clock > 1 then "big" else "small"
+            }"#,
+        );
+        let stdout = rte.output("stdout").unwrap();
+        let clock = rte.input("clock").unwrap();
+
+        rte.record_all_history(HistoryLimit::Unbounded);
+        rte.pull_once(stdout);
+
+        let start = rte.current_time();
+        for t in 1..=3 {
+            rte.put_current(clock, VarType::Int(t));
+        }
+
+        assert_eq!(rte.value_at(stdout, start), Some(VarType::from_string("small")));
+        assert_eq!(rte.value_at(stdout, start + 1), Some(VarType::from_string("small")));
+        assert_eq!(rte.value_at(stdout, start + 2), Some(VarType::from_string("big")));
+        assert_eq!(rte.value_at(clock, start + 3), Some(VarType::Int(3)));
+    }
+
+    #[test]
+    fn test_index_out_of_range() {
+        let mut rte = runtime(
+            r#"mod main() {
+                out c = "abc"[clock]
+                out s = (split("a,b", ","))[clock]
+                0
+            }"#,
+        );
+        let (c, s, clock) = (rte.output("c").unwrap(), rte.output("s").unwrap(), rte.input("clock").unwrap());
+
+        rte.put_current(clock, VarType::Int(1));
+        assert_eq!((rte.pull_once(c), rte.pull_once(s)), (VarType::Char('b'), VarType::from("b")));
+        assert!(rte.take_errors().is_empty());
+
+        let cases = [
+            (-1, VarType::Null, vec![2, 3]),
+            (2, VarType::Char('c'), vec![2]),
+            (7, VarType::Null, vec![2, 3]),
+        ];
+        for (i, char, lens) in &cases {
+            rte.put_current(clock, VarType::Int(*i));
+            assert_eq!((rte.pull_once(c), rte.pull_once(s)), (char.clone(), VarType::Null));
+
+            let errors = rte.take_errors();
+            let mut reported: Vec<usize> = errors
+                .iter()
+                .map(|e| match e {
+                    RuntimeError::IndexOutOfRange { index, len, .. } if index == i => *len,
+                    e => panic!("unexpected error {}", e),
+                })
+                .collect();
+            reported.sort();
+            assert_eq!(&reported, lens);
+        }
+    }
+
+    #[test]
+    fn test_append_keeps_every_value() {
+        let mut rte = runtime(
+            r#"mod main() {
+                stdin <> "!"
+            }"#,
+        );
+        let (stdout, stdin, clock) = (
+            rte.output("stdout").unwrap(),
+            rte.input("stdin").unwrap(),
+            rte.input("clock").unwrap(),
+        );
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let _handle = rte.listen(stdout, false, ListenMode::OnChange, Box::new(move |_, v| tx.send(v.clone()).unwrap()));
+
+        let start = rte.current_time;
+        let time = rte
+            .begin()
+            .append(stdin, VarType::from("one"))
+            .set(clock, VarType::Int(1))
+            .set(clock, VarType::Int(2))
+            .append(stdin, VarType::from("two"))
+            .commit();
+
+        assert_eq!(time, start + 2);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![VarType::from("one!"), VarType::from("two!")]);
+        assert_eq!(rte.pull_once(clock), VarType::Int(2));
+    }
+
+    #[test]
+    fn test_listener_handles() {
+        let mut rte = runtime(
+            r#"mod main() {
+                clock + 1
+            }"#,
+        );
+        let stdout = rte.output("stdout").unwrap();
+        let clock = rte.input("clock").unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut seen = Vec::new();
+
+        let handle = rte.listen(
+            stdout,
+            true,
+            ListenMode::OnChange,
+            Box::new(move |_, v| {
+                seen.push(v.unpack_int().unwrap());
+                tx.send(seen.clone()).unwrap();
+            }),
+        );
+
+        rte.put_current(clock, VarType::Int(1));
+        rte.put_current(clock, VarType::Int(2));
+
+        assert!(handle.is_active());
+        handle.cancel();
+
+        rte.put_current(clock, VarType::Int(3));
+
+        assert_eq!(rx.try_iter().last(), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_memoized_applications() {
+        let mut rte = runtime(
+            r#"mod main() {
+                mod double(x : int) {
+                    x + x
+                }
+                double(clock)
+            }"#,
+        );
+        rte.enable_memoization(16);
+
+        let stdout = rte.output("stdout").unwrap();
+        let clock = rte.input("clock").unwrap();
+        let instantiated_before = rte.stats().instantiated_nodes;
+
+        for t in 0..100 {
+            rte.put_current(clock, VarType::Int(t % 3));
+            assert_eq!(rte.pull_once(stdout).unpack_int(), Some(2 * (t % 3)));
+        }
+
+        // Only the first occurrence of each argument value was computed.
+        assert_eq!(rte.stats().instantiated_nodes - instantiated_before, 3);
+        assert_eq!(rte.stats().memo_hits, 97);
+    }
+
+    #[test]
+    fn test_memoization_skips_impure_and_opted_out() {
+        for src in &[
+            r#"mod main() {
+                mod plus_clock(x : int) {
+                    x + clock
+                }
+                plus_clock(clock)
+            }"#,
+            r#"mod main() {
+                nomemo mod plus_clock(x : int) {
+                    x + x
+                }
+                plus_clock(clock)
+            }"#,
+        ] {
+            let mut rte = runtime(src);
+            rte.enable_memoization(16);
+
+            let stdout = rte.output("stdout").unwrap();
+            let clock = rte.input("clock").unwrap();
+
+            for t in 0..10 {
+                rte.put_current(clock, VarType::Int(t % 2));
+                assert_eq!(rte.pull_once(stdout).unpack_int(), Some(2 * (t % 2)));
+            }
+
+            assert_eq!(rte.stats().memo_hits, 0);
+        }
+    }
+
+    #[test]
+    fn test_instance_reused_when_inputs_change() {
+        let (small_computations, small_instantiated) = work_per_tick(10);
+        let (large_computations, large_instantiated) = work_per_tick(200);
+
+        assert_eq!(small_instantiated, 0);
+        assert_eq!(large_instantiated, 0);
+        assert_eq!(small_computations, large_computations);
+    }
+
+    #[test]
+    fn test_get_and_clamp() {
+        let outputs = |output: &str| {
+            let mut rte = runtime(&format!("mod main() {{\n t = \"abc\"\n {}\n}}", output));
+            let (stdout, clock) = (rte.output("stdout").unwrap(), rte.input("clock").unwrap());
+            [0, 5, -1]
+                .iter()
+                .map(|t| {
+                    rte.put_current(clock, VarType::Int(*t));
+                    rte.pull_once(stdout)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            outputs("get(t, clock, \"-\")"),
+            vec![VarType::Char('a'), VarType::from_string("-"), VarType::from_string("-")]
+        );
+        assert_eq!(
+            outputs("t[clamp(clock, 0, 2)]"),
+            vec![VarType::Char('a'), VarType::Char('c'), VarType::Char('a')]
+        );
+    }
+
+    /// The value of an expression, as the result of a program.
+    fn eval(expression: &str) -> VarType {
+        let mut rte = runtime(&format!("mod main() {{\n {}\n}}", expression));
+        let stdout = rte.output("stdout").unwrap();
+        rte.pull_once(stdout)
+    }
+
+    #[test]
+    fn test_string_builtins() {
+        let strs = |parts: &[&str]| VarType::from(parts.iter().map(|p| VarType::from(*p)).collect::<Vec<_>>());
+
+        assert_eq!(eval(r#"split("a,b,,c", ",")"#), strs(&["a", "b", "", "c"]));
+        assert_eq!(eval(r#"split("abc", "")"#), strs(&["a", "b", "c"]));
+        assert_eq!(eval(r#"trim("  padded \t")"#), VarType::from("padded"));
+        assert_eq!(eval(r#"substr("héllo", 1, 3)"#), VarType::from("éll"));
+        assert_eq!(eval(r#"substr("abc", 2, 10)"#), VarType::from("c"));
+        assert_eq!(eval(r#"find("héllo", "l")"#), VarType::Int(2));
+        assert_eq!(eval(r#"find("abc", "x")"#), VarType::Int(-1));
+        assert_eq!(eval(r#"replace("a-b-c", "-", "+")"#), VarType::from("a+b+c"));
+        assert_eq!(eval(r#"to_upper("abc")"#), VarType::from("ABC"));
+        assert_eq!(eval(r#"parse_int(" 42 ")"#), VarType::Int(42));
+        assert_eq!(eval(r#"parse_int("forty")"#), VarType::Null);
+        assert_eq!(eval(r#"len("héllo")"#), VarType::Int(5));
+    }
+
+    #[test]
+    fn test_null_and_mixed_operands() {
+        // Null from a failed parse propagates instead of panicking.
+        assert_eq!(eval(r#"(parse_int("x")) + 1"#), VarType::Null);
+        assert_eq!(eval(r#"trim(parse_int("x"))"#), VarType::Null);
+        assert_eq!(eval(r#"(parse_int("x")) <> "a""#), VarType::Null);
+
+        // A string concatenated with a vector is a vector of characters.
+        assert_eq!(
+            eval(r#""ab" <> (split("c", ","))"#),
+            VarType::from(vec![VarType::Char('a'), VarType::Char('b'), VarType::from("c")])
+        );
+
+        assert_eq!(eval(r#""abc"[5]"#), VarType::Null);
+    }
+
+    #[test]
+    fn test_range() {
+        let ints = |v: Vec<i64>| VarType::from(v);
+        assert_eq!(eval("0..4"), ints(vec![0, 1, 2, 3]));
+        assert_eq!(eval("-2..1"), ints(vec![-2, -1, 0]));
+        assert_eq!(eval("3..3"), ints(vec![]));
+        assert_eq!(eval("(0..10)[9]"), VarType::Int(9));
+        assert_eq!(eval(r#"(parse_int("x"))..3"#), VarType::Null);
+    }
+
+    #[test]
+    fn test_string_literal_node_count() {
+        let nodes = |literal: &str| {
+            let src = format!("mod main() {{\n a = \"{0}\"\n b = \"{0}\"\n a <> b\n}}", literal);
+            let mut rte = runtime(&src);
+            let stdout = rte.output("stdout").unwrap();
+            rte.pull_once(stdout);
+            rte.node_count()
+        };
+
+        let short = nodes("x");
+        let long = nodes(&"lorem ipsum ".repeat(100));
+
+        // Literals are one node each, where they used to take a node per
+        // character, or 2400 nodes for the long literals here.
+        assert_eq!(short, long);
+        assert!(long < 100);
     }
 }
@@ -0,0 +1,114 @@
+//! Errors detected while running a program, which are reported without
+//! stopping it.
+
+use super::Time;
+use crate::ast::Type;
+use crate::program::{Claim, VarType};
+use itertools::join;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuntimeError {
+    /// A claim that couldn't be verified statically turned out false.
+    ClaimFailed {
+        time: Time,
+        /// Stable id of the node checking the claim.
+        node: String,
+        claim: Rc<Claim>,
+        /// Values of the names the claim refers to, at the failing tick.
+        values: Vec<(String, VarType)>,
+    },
+    /// A value of the wrong type was put on a typed input, and ignored.
+    InputType {
+        time: Time,
+        input: String,
+        expected: Type,
+        value: VarType,
+    },
+    /// An index was negative or past the end of the string or vector.
+    IndexOutOfRange {
+        time: Time,
+        /// Stable id of the node doing the indexing.
+        node: String,
+        index: i64,
+        len: usize,
+    },
+    /// A native function returned a `NativeError`, to which the runtime adds
+    /// where it happened. The calling node keeps its previous value.
+    Native {
+        time: Time,
+        /// Stable id of the node calling the function.
+        node: String,
+        function: String,
+        message: String,
+    },
+    /// The host failed to perform an effect the program asked for.
+    Effect {
+        time: Time,
+        /// Stable id of the node asking for the effect.
+        node: String,
+        effect: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::ClaimFailed {
+                time,
+                node,
+                claim,
+                values,
+            } => {
+                write!(
+                    f,
+                    "at tick {}: {} `{}` failed in {}",
+                    time,
+                    claim.kind.keyword(),
+                    claim.source,
+                    node
+                )?;
+                if !values.is_empty() {
+                    let values = values
+                        .iter()
+                        .map(|(name, value)| format!("{} = {}", name, value.describe()));
+                    write!(f, ", with {}", join(values, ", "))?;
+                }
+                Ok(())
+            }
+            RuntimeError::InputType {
+                time,
+                input,
+                expected,
+                value,
+            } => write!(
+                f,
+                "at tick {}: {} expects a value of type {:?}, got {}",
+                time,
+                input,
+                expected,
+                value.describe()
+            ),
+            RuntimeError::IndexOutOfRange {
+                time,
+                node,
+                index,
+                len,
+            } => write!(f, "at tick {}: index {} out of range for length {} in {}", time, index, len, node),
+            RuntimeError::Native {
+                time,
+                node,
+                function,
+                message,
+            } => write!(f, "at tick {}: {} failed in {}: {}", time, function, node, message),
+            RuntimeError::Effect {
+                time,
+                node,
+                effect,
+                message,
+            } => write!(f, "at tick {}: {} failed in {}: {}", time, effect, node, message),
+        }
+    }
+}
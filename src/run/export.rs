@@ -0,0 +1,269 @@
+//! Export of the dataflow graph of a runtime, for visualizing and diffing
+//! the structure of programs.
+
+use super::{Application, RuntimeEnv};
+use crate::program::NodeIndex;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Graphviz DOT, with fragment instances drawn as clusters.
+    Dot,
+    Json,
+}
+
+struct GraphNode {
+    id: String,
+    operation: String,
+    fragment: Option<String>,
+    value: Option<String>,
+    last_update: u64,
+    /// Number of nodes this one stands for, when instances are collapsed.
+    size: usize,
+}
+
+struct GraphEdge {
+    from: String,
+    to: String,
+    /// `data` for an operand, or `output` from the output of a fragment
+    /// instance to the node applying the fragment.
+    kind: &'static str,
+}
+
+/// The instance a node belongs to, which is the id of the node that applied
+/// the fragment, or `None` for nodes outside of fragment instances.
+fn instance_of(id: &str) -> Option<&str> {
+    id.rfind('/').map(|i| &id[..i])
+}
+
+impl RuntimeEnv {
+    /// Describe every node and edge of the dataflow graph. If `collapse` is
+    /// set, the nodes of each fragment instance are merged into a single node
+    /// named after the instance.
+    pub fn export_graph(&self, format: GraphFormat, collapse: bool) -> String {
+        let (nodes, edges) = self.graph(collapse);
+
+        match format {
+            GraphFormat::Dot => to_dot(&nodes, &edges),
+            GraphFormat::Json => to_json(self.current_time, &nodes, &edges),
+        }
+    }
+
+    fn graph(&self, collapse: bool) -> (Vec<GraphNode>, Vec<GraphEdge>) {
+        let name = |idx: NodeIndex| -> String {
+            let id = &self.nodes[idx.0].id;
+            match instance_of(id) {
+                Some(instance) if collapse => instance.to_string(),
+                _ => id.clone(),
+            }
+        };
+
+        let mut nodes: BTreeMap<String, GraphNode> = BTreeMap::new();
+        let mut edges: BTreeSet<(String, String, &'static str)> = BTreeSet::new();
+
+        for (i, node) in self.nodes.iter() {
+            let idx = NodeIndex(i);
+            let graph_name = name(idx);
+
+            if collapse && instance_of(&node.id).is_some() {
+                let entry = nodes.entry(graph_name.clone()).or_insert_with(|| GraphNode {
+                    id: graph_name.clone(),
+                    operation: "instance".to_string(),
+                    fragment: node.origin.clone(),
+                    value: None,
+                    last_update: 0,
+                    size: 0,
+                });
+                entry.size += 1;
+                entry.last_update = entry.last_update.max(node.last_update);
+            } else {
+                nodes.insert(
+                    graph_name.clone(),
+                    GraphNode {
+                        id: graph_name.clone(),
+                        operation: node.operation.label(),
+                        fragment: node.origin.clone(),
+                        value: node.value_cache.as_ref().map(|v| v.describe()),
+                        last_update: node.last_update,
+                        size: 1,
+                    },
+                );
+            }
+
+            for dep in node.operation.dependencies() {
+                edges.insert((name(dep), graph_name.clone(), "data"));
+            }
+
+            if let Some(Application::Instance(_, instance)) = &node.application {
+                edges.insert((name(instance.output), graph_name.clone(), "output"));
+            }
+        }
+
+        let edges = edges
+            .into_iter()
+            // Edges within a collapsed instance.
+            .filter(|(from, to, _)| from != to)
+            .map(|(from, to, kind)| GraphEdge { from, to, kind })
+            .collect();
+
+        (nodes.into_values().collect(), edges)
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("{:?}", s)
+}
+
+fn to_dot(nodes: &[GraphNode], edges: &[GraphEdge]) -> String {
+    let mut clusters: BTreeMap<Option<&str>, Vec<&GraphNode>> = BTreeMap::new();
+    for node in nodes {
+        let cluster = if node.size == 1 { instance_of(&node.id) } else { None };
+        clusters.entry(cluster).or_default().push(node);
+    }
+
+    let mut out = String::from("digraph tempura {\n    node [shape=box];\n");
+
+    for (cluster, nodes) in clusters {
+        let indent = match cluster {
+            Some(instance) => {
+                out += &format!("    subgraph {} {{\n", quote(&format!("cluster_{}", instance)));
+                out += &format!("        label = {};\n", quote(instance));
+                "        "
+            }
+            None => "    ",
+        };
+
+        for node in nodes {
+            let mut label = format!("{}\n{}", node.id, node.operation);
+            if node.size > 1 {
+                label += &format!(" of {} ({} nodes)", node.fragment.as_deref().unwrap_or(""), node.size);
+            }
+            if let Some(value) = &node.value {
+                label += &format!("\n= {}", value);
+            }
+            label += &format!("\n@{}", node.last_update);
+            out += &format!("{}{} [label={}];\n", indent, quote(&node.id), quote(&label));
+        }
+
+        if cluster.is_some() {
+            out += "    }\n";
+        }
+    }
+
+    for edge in edges {
+        let style = if edge.kind == "output" { " [style=dashed]" } else { "" };
+        out += &format!("    {} -> {}{};\n", quote(&edge.from), quote(&edge.to), style);
+    }
+
+    out + "}\n"
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out + "\""
+}
+
+fn json_option(s: &Option<String>) -> String {
+    match s {
+        Some(s) => json_string(s),
+        None => "null".to_string(),
+    }
+}
+
+fn to_json(time: u64, nodes: &[GraphNode], edges: &[GraphEdge]) -> String {
+    let nodes: Vec<String> = nodes
+        .iter()
+        .map(|n| {
+            format!(
+                "    {{\"id\": {}, \"operation\": {}, \"fragment\": {}, \"value\": {}, \"last_update\": {}, \"size\": {}}}",
+                json_string(&n.id),
+                json_string(&n.operation),
+                json_option(&n.fragment),
+                json_option(&n.value),
+                n.last_update,
+                n.size
+            )
+        })
+        .collect();
+
+    let edges: Vec<String> = edges
+        .iter()
+        .map(|e| {
+            format!(
+                "    {{\"from\": {}, \"to\": {}, \"kind\": {}}}",
+                json_string(&e.from),
+                json_string(&e.to),
+                json_string(e.kind)
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\n  \"time\": {},\n  \"nodes\": [\n{}\n  ],\n  \"edges\": [\n{}\n  ]\n}}\n",
+        time,
+        nodes.join(",\n"),
+        edges.join(",\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::build_runtime;
+    use crate::nom_parse::parse_tempura;
+    use nom::error::VerboseError;
+
+    fn runtime() -> RuntimeEnv {
+        let mut rte = build_runtime(
+            parse_tempura::<VerboseError<&str>>(
+                r#"mod main() {
+                    mod double(x : int) {
+                        x + x
+                    }
+                    y = double(clock)
+                    y + 1
+                }"#,
+            )
+            .unwrap()
+            .1,
+        )
+        .unwrap();
+        rte.pull_once(rte.output("stdout").unwrap());
+        rte
+    }
+
+    #[test]
+    fn test_export_dot() {
+        let dot = runtime().export_graph(GraphFormat::Dot, false);
+
+        assert!(dot.starts_with("digraph tempura {"));
+        assert!(dot.contains("subgraph \"cluster_main/y\""));
+        assert!(dot.contains("\"clock\" -> \"main/y\";"));
+        assert!(dot.contains("\"main/double\" -> \"main/y\";"));
+    }
+
+    #[test]
+    fn test_export_collapsed_json() {
+        let rte = runtime();
+        let json = rte.export_graph(GraphFormat::Json, true);
+
+        // The instance of main collapses into one node, and the instance of
+        // double applied in it into another.
+        assert!(json.contains("{\"id\": \"main\", \"operation\": \"instance\", \"fragment\": \"main\""));
+        assert!(json.contains("{\"id\": \"main/y\", \"operation\": \"instance\", \"fragment\": \"double\""));
+        assert!(json.contains("{\"from\": \"clock\", \"to\": \"main\", \"kind\": \"data\"}"));
+        assert!(json.contains("{\"from\": \"main/y\", \"to\": \"main\", \"kind\": \"output\"}"));
+        assert!(!json.contains("\"from\": \"main\", \"to\": \"main\""));
+    }
+}
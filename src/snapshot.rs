@@ -0,0 +1,298 @@
+//! Saving the state of a running program to a file, and resuming it from
+//! there after rebuilding the program from source.
+//!
+//! A snapshot starts with a `tempura-snapshot <version>` line and the logical
+//! time, followed by one `node <stable id> <last update> <input> <value>`
+//! line for every node with a value, where `<input>` tells whether the value
+//! was put on the node from outside: those of external nodes and the results
+//! of effects. Only those values are restored: all other values are functions
+//! of them and are recomputed, and compared with the saved ones to detect that
+//! the program has changed.
+
+use crate::encoding::{decode_value, encode_value};
+use crate::program::{Operation, VarType};
+use crate::run::{RuntimeEnv, Time};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The snapshot was written by an incompatible version.
+    UnsupportedVersion(String),
+    /// The line with the given number couldn't be parsed.
+    Malformed(usize),
+}
+
+/// What happened to the saved state when resuming.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RestoreReport {
+    /// Number of external and effect nodes whose value was restored.
+    pub restored: usize,
+    /// Saved nodes that don't exist in the rebuilt program, which includes
+    /// those whose definition changed.
+    pub missing: Vec<String>,
+    /// Nodes that were recomputed to a different value than was saved.
+    pub changed: Vec<String>,
+}
+
+pub fn write_snapshot(rte: &RuntimeEnv, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "tempura-snapshot {}", SNAPSHOT_VERSION)?;
+    writeln!(out, "time {}", rte.current_time())?;
+
+    for idx in rte.node_indices() {
+        if let Some((t, value)) = rte.cached_value(idx) {
+            // Fragments are code, which is rebuilt from source.
+            if let VarType::Fragment(_) = value {
+                continue;
+            }
+
+            let input = matches!(rte.operation(idx), Operation::External | Operation::Effect(_, _));
+            writeln!(
+                out,
+                "node {} {} {} {}",
+                rte.stable_id(idx),
+                t,
+                input,
+                encode_value(value)
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+struct SavedNode {
+    id: String,
+    last_update: Time,
+    input: bool,
+    value: VarType,
+}
+
+fn parse_snapshot(snapshot: &str) -> Result<(Time, Vec<SavedNode>), SnapshotError> {
+    let mut lines = snapshot.lines().enumerate();
+
+    match lines.next() {
+        Some((_, line)) if line.starts_with("tempura-snapshot ") => {
+            let version = &line["tempura-snapshot ".len()..];
+            if version != SNAPSHOT_VERSION.to_string() {
+                return Err(SnapshotError::UnsupportedVersion(version.to_string()));
+            }
+        }
+        _ => return Err(SnapshotError::Malformed(1)),
+    }
+
+    let time = match lines.next() {
+        Some((_, line)) if line.starts_with("time ") => line["time ".len()..]
+            .parse::<Time>()
+            .map_err(|_| SnapshotError::Malformed(2))?,
+        _ => return Err(SnapshotError::Malformed(2)),
+    };
+
+    let mut nodes = Vec::new();
+    for (i, line) in lines {
+        let mut parts = line.splitn(5, ' ');
+        let node = match (
+            parts.next(),
+            parts.next(),
+            parts.next().and_then(|t| t.parse::<Time>().ok()),
+            parts.next().and_then(|e| e.parse::<bool>().ok()),
+            parts.next().and_then(decode_value),
+        ) {
+            (Some("node"), Some(id), Some(last_update), Some(input), Some(value)) => SavedNode {
+                id: id.to_string(),
+                last_update,
+                input,
+                value,
+            },
+            _ => return Err(SnapshotError::Malformed(i + 1)),
+        };
+        nodes.push(node);
+    }
+
+    Ok((time, nodes))
+}
+
+/// Restore a snapshot into a freshly built runtime of the same program.
+pub fn restore_snapshot(rte: &mut RuntimeEnv, snapshot: &str) -> Result<RestoreReport, SnapshotError> {
+    let (time, saved) = parse_snapshot(snapshot)?;
+    let mut report = RestoreReport::default();
+
+    let mut externals = Vec::new();
+    let mut effects = HashMap::new();
+    for node in saved.iter().filter(|n| n.input) {
+        match rte.find_node(&node.id) {
+            Some(idx) if *rte.operation(idx) == Operation::External => {
+                externals.push((idx, node.last_update, node.value.clone()))
+            }
+            // Effects take their saved result when they are asked for again,
+            // including those of fragment instances that don't exist yet.
+            _ => {
+                effects.insert(node.id.clone(), node.value.clone());
+            }
+        }
+    }
+
+    report.restored = externals.len();
+    rte.restore(time, externals);
+    rte.restore_effects(effects);
+
+    for output in rte.outputs().map(|(_, idx)| idx).collect::<Vec<_>>() {
+        rte.pull_once(output);
+    }
+
+    // Nodes of fragment instances only exist once they have been computed,
+    // which is why this happens after pulling the outputs.
+    for node in saved.iter() {
+        match rte.find_node(&node.id) {
+            Some(idx) if node.input => {
+                if let Operation::Effect(_, _) = rte.operation(idx) {
+                    report.restored += 1;
+                }
+            }
+            Some(idx) => {
+                if let Some((_, v)) = rte.cached_value(idx) {
+                    if *v != node.value {
+                        report.changed.push(node.id.clone());
+                    }
+                }
+            }
+            None => report.missing.push(node.id.clone()),
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::build_runtime;
+    use crate::nom_parse::parse_tempura;
+    use nom::error::VerboseError;
+
+    fn runtime(src: &str) -> RuntimeEnv {
+        build_runtime(parse_tempura::<VerboseError<&str>>(src).unwrap().1).unwrap()
+    }
+
+    const SRC: &str = r#"mod main() {
+        mod greet(name : str) {
+            "hello $name"
+        }
+        if clock > 2 then greet(stdin) else "waiting"
+    }"#;
+
+    fn snapshot_after_run() -> String {
+        let mut rte = runtime(SRC);
+        let (stdin, clock) = (rte.input("stdin").unwrap(), rte.input("clock").unwrap());
+        rte.put_current(stdin, VarType::from_string("world"));
+        rte.put_current(clock, VarType::Int(5));
+        rte.pull_once(rte.output("stdout").unwrap());
+
+        let mut out = Vec::new();
+        write_snapshot(&rte, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_resume() {
+        let snapshot = snapshot_after_run();
+
+        let mut rte = runtime(SRC);
+        let report = restore_snapshot(&mut rte, &snapshot).unwrap();
+
+        assert_eq!(
+            report,
+            RestoreReport {
+                restored: 2,
+                missing: vec![],
+                changed: vec![],
+            }
+        );
+        assert_eq!(rte.current_time(), 3);
+        assert_eq!(
+            rte.pull_once(rte.output("stdout").unwrap()),
+            VarType::from_string("hello world")
+        );
+
+        // The resumed program keeps running from where it was.
+        rte.put_current(rte.input("stdin").unwrap(), VarType::from_string("again"));
+        assert_eq!(
+            rte.pull_once(rte.output("stdout").unwrap()),
+            VarType::from_string("hello again")
+        );
+    }
+
+    #[test]
+    fn test_resume_changed_program() {
+        let snapshot = snapshot_after_run();
+
+        let mut rte = runtime(&SRC.replace("hello", "bye"));
+        let report = restore_snapshot(&mut rte, &snapshot).unwrap();
+
+        assert_eq!(report.restored, 2);
+        assert!(!report.missing.is_empty());
+    }
+
+    #[test]
+    fn test_resume_effects() {
+        use crate::effects::{start_requested, FakeEffects};
+        use crate::program::EffectKind;
+        use std::sync::mpsc;
+
+        let src = r#"mod main() {
+            out config = file_contents("config")
+            every(100ms)
+        }"#;
+        let config = (EffectKind::FileContents, vec![VarType::from("config")]);
+
+        let run = |rte: &mut RuntimeEnv, fake: &mut FakeEffects, ms: u64| {
+            let (tx, rx) = mpsc::channel();
+            rte.pull_once(rte.output("stdout").unwrap());
+            rte.pull_once(rte.output("config").unwrap());
+            start_requested(rte, fake, move |result| tx.send(result).unwrap());
+            fake.finish_all();
+            fake.advance(ms);
+            for result in rx.try_iter() {
+                rte.complete(result);
+            }
+        };
+
+        let mut rte = runtime(src);
+        let mut fake = FakeEffects::new();
+        fake.set_result(config.0, config.1.clone(), Ok("a = 1".into()));
+        run(&mut rte, &mut fake, 300);
+
+        let mut out = Vec::new();
+        write_snapshot(&rte, &mut out).unwrap();
+        let snapshot = String::from_utf8(out).unwrap();
+
+        // The results are back before the effects deliver new ones.
+        let mut rte = runtime(src);
+        let report = restore_snapshot(&mut rte, &snapshot).unwrap();
+
+        // The clock, and the results of both effects.
+        assert_eq!(report.restored, 3);
+        assert!(report.missing.is_empty());
+        assert_eq!(rte.pull_once(rte.output("stdout").unwrap()), VarType::Int(3));
+        assert_eq!(rte.pull_once(rte.output("config").unwrap()), VarType::from("a = 1"));
+
+        // The effects are asked for again, and the timer keeps counting.
+        let mut fake = FakeEffects::new();
+        fake.set_result(config.0, config.1.clone(), Ok("a = 2".into()));
+        run(&mut rte, &mut fake, 100);
+        assert_eq!(rte.pull_once(rte.output("stdout").unwrap()), VarType::Int(4));
+        assert_eq!(rte.pull_once(rte.output("config").unwrap()), VarType::from("a = 2"));
+    }
+
+    #[test]
+    fn test_rejects_other_versions() {
+        let snapshot = snapshot_after_run().replacen("tempura-snapshot 1", "tempura-snapshot 0", 1);
+
+        assert_eq!(
+            restore_snapshot(&mut runtime(SRC), &snapshot),
+            Err(SnapshotError::UnsupportedVersion("0".to_string()))
+        );
+    }
+}
@@ -1,18 +1,614 @@
+//! Static verification of claims about the values of a fragment, by symbolic
+//! evaluation of the nodes it is built from.
+//!
+//! Integers are represented as linear combinations of opaque values, such as
+//! inputs or the results of fragment applications, together with the range
+//! each opaque value is known to lie in, and whether it may be null. Claims
+//! that compare integers are decided from the range of the difference between
+//! both sides, so that e.g. `c < c + 1` holds for `c = clamp(x, 0, 9)`, and
+//! `len(s) >= 0` for any string `s`.
+//!
+//! At runtime, a sum that overflows and an operation on a null are null, and
+//! null is less than every integer. Comparisons are therefore only decided
+//! when neither side may be null, and sums are only kept symbolic when their
+//! range shows that they can't overflow. The only values known not to be null
+//! are constants and the typed parameters of the main module, which are
+//! checked when they are set.
 
-use crate::build::fragment_builder::{FragmentBuilder};
-use crate::program::LacunaryRef;
-use crate::program::Operation;
+use crate::ast::{BinaryOp, Type};
+use crate::build::fragment_builder::FragmentBuilder;
+use crate::program::{LacunaryRef, Operation, StringOp, VarType};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 
-enum VerifyResult {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyResult {
     True,
     False,
-    Unsure
+    Unsure,
 }
 
-pub fn verify_claim(env: &FragmentBuilder, lr: &LacunaryRef, claim: &Operation<LacunaryRef>) -> bool {
+/// The outcome of verifying a claim, with an explanation for humans.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    pub result: VerifyResult,
+    pub reason: String,
+}
+
+/// Decide whether the boolean value `claim` always holds, never holds, or
+/// whether that can't be told statically.
+pub fn verify_claim(env: &FragmentBuilder, claim: &LacunaryRef) -> Verdict {
+    let mut evaluator = Evaluator::new(env);
+    let depth = evaluator.frames.len() - 1;
+
+    let result = evaluator.eval(depth, claim);
+
+    let (result, default_reason) = match result {
+        Sym::Bool(Some(true)) => (VerifyResult::True, "the claim is constant"),
+        Sym::Bool(Some(false)) => (VerifyResult::False, "the claim is constant"),
+        _ => (
+            VerifyResult::Unsure,
+            "the claim depends on values that can't be determined statically",
+        ),
+    };
+
+    Verdict {
+        result,
+        reason: evaluator
+            .reason
+            .unwrap_or_else(|| default_reason.to_string()),
+    }
+}
+
+/// Decide whether indexing `container` with `index` stays within bounds.
+pub fn verify_index(env: &FragmentBuilder, container: &LacunaryRef, index: &LacunaryRef) -> Verdict {
+    let mut evaluator = Evaluator::new(env);
+    let depth = evaluator.frames.len() - 1;
+
+    let index = match evaluator.eval(depth, index) {
+        Sym::Int(l) => evaluator.interval(&l),
+        _ => Interval::ANY,
+    };
+    let length = match evaluator.eval(depth, container) {
+        Sym::Value(VarType::Str(s)) => Some(s.chars().count() as i64),
+        Sym::Value(VarType::Vector(v)) => Some(v.len() as i64),
+        _ => None,
+    };
+
+    let below = index.hi.map_or(false, |hi| hi < 0);
+    let above = match length {
+        Some(n) => index.lo.map_or(false, |lo| lo >= n),
+        None => false,
+    };
+    let within = index.lo.map_or(false, |lo| lo >= 0)
+        && match length {
+            Some(n) => index.hi.map_or(false, |hi| hi < n),
+            None => false,
+        };
+
+    let result = if below || above {
+        VerifyResult::False
+    } else if within {
+        VerifyResult::True
+    } else {
+        VerifyResult::Unsure
+    };
+
+    let reason = match length {
+        Some(n) => format!("the index lies in {} and the length is {}", index, n),
+        None => format!("the index lies in {} and the length is unknown", index),
+    };
+
+    Verdict { result, reason }
+}
+
+/// A value that isn't analysed any further: the depth of the fragment it
+/// belongs to and a reference to it within that fragment.
+type Atom = (usize, LacunaryRef);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Interval {
+    lo: Option<i64>,
+    hi: Option<i64>,
+}
+
+impl Interval {
+    const ANY: Interval = Interval { lo: None, hi: None };
+
+    fn exactly(i: i64) -> Interval {
+        Interval {
+            lo: Some(i),
+            hi: Some(i),
+        }
+    }
+
+    fn hull(self, other: Interval) -> Interval {
+        Interval {
+            lo: self.lo.and_then(|a| other.lo.map(|b| a.min(b))),
+            hi: self.hi.and_then(|a| other.hi.map(|b| a.max(b))),
+        }
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.lo {
+            Some(lo) => write!(f, "[{}, ", lo)?,
+            None => write!(f, "(-inf, ")?,
+        }
+        match self.hi {
+            Some(hi) => write!(f, "{}]", hi),
+            None => write!(f, "inf)"),
+        }
+    }
+}
+
+/// `constant + sum(coefficient * atom)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Linear {
+    terms: BTreeMap<Atom, i64>,
+    constant: i64,
+}
+
+impl Linear {
+    fn constant(i: i64) -> Linear {
+        Linear {
+            terms: BTreeMap::new(),
+            constant: i,
+        }
+    }
+
+    fn atom(atom: Atom) -> Linear {
+        let mut terms = BTreeMap::new();
+        terms.insert(atom, 1);
+        Linear { terms, constant: 0 }
+    }
+
+    /// `self + factor * other`, or `None` on overflow.
+    fn add_scaled(&self, other: &Linear, factor: i64) -> Option<Linear> {
+        let mut result = self.clone();
+        result.constant = result.constant.checked_add(other.constant.checked_mul(factor)?)?;
+        for (atom, coefficient) in other.terms.iter() {
+            let c = result.terms.entry(*atom).or_insert(0);
+            *c = c.checked_add(coefficient.checked_mul(factor)?)?;
+            if *c == 0 {
+                result.terms.remove(atom);
+            }
+        }
+        Some(result)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Sym {
+    /// An integer, null if one of its atoms may be and is.
+    Int(Linear),
+    /// A boolean that isn't null, if known.
+    Bool(Option<bool>),
+    /// A known value other than an integer or boolean.
+    Value(VarType),
+    /// A string that isn't null.
+    Str,
+    Unknown,
+}
+
+struct Evaluator<'b, 'a> {
+    /// The fragments being built, from the outermost to the one the claim is in.
+    frames: Vec<&'b FragmentBuilder<'a>>,
+    bounds: BTreeMap<Atom, Interval>,
+    /// Opaque values that may be null at runtime.
+    nullable: BTreeSet<Atom>,
+    cache: BTreeMap<Atom, Sym>,
+    /// Explanation of the last comparison that was evaluated.
+    reason: Option<String>,
+}
+
+impl<'b, 'a> Evaluator<'b, 'a> {
+    fn new(env: &'b FragmentBuilder<'a>) -> Evaluator<'b, 'a> {
+        let mut frames = vec![env];
+        while let Some(parent) = frames.last().unwrap().parent() {
+            frames.push(parent);
+        }
+        frames.reverse();
+
+        Evaluator {
+            frames,
+            bounds: BTreeMap::new(),
+            nullable: BTreeSet::new(),
+            cache: BTreeMap::new(),
+            reason: None,
+        }
+    }
+
+    /// Find the fragment a reference from the fragment at `depth` points into.
+    fn resolve(&self, depth: usize, lr: &LacunaryRef) -> Atom {
+        match lr {
+            LacunaryRef::InputRef { up, index } => (depth - up, LacunaryRef::InputRef { up: 0, index: *index }),
+            LacunaryRef::ContextRef { up, index } => {
+                (depth - up, LacunaryRef::ContextRef { up: 0, index: *index })
+            }
+            LacunaryRef::InstanciatedRef(_) => (0, *lr),
+        }
+    }
 
-    
+    fn eval(&mut self, depth: usize, lr: &LacunaryRef) -> Sym {
+        let atom = self.resolve(depth, lr);
 
-    unimplemented!()
+        if let Some(sym) = self.cache.get(&atom) {
+            return sym.clone();
+        }
 
-}
\ No newline at end of file
+        let sym = match atom.1 {
+            LacunaryRef::ContextRef { index, .. } => match self.frames[atom.0].operation(index) {
+                Some(op) => {
+                    let op = op.clone();
+                    self.eval_operation(atom, &op)
+                }
+                None => self.opaque(atom, Interval::ANY),
+            },
+            // Values put on typed parameters of the main module are checked,
+            // and they start from the default of their type.
+            LacunaryRef::InputRef { .. } if atom.0 == 1 && self.frames[1].type_of(&atom.1).is_some() => {
+                self.not_null(atom)
+            }
+            _ => self.opaque(atom, Interval::ANY),
+        };
+
+        self.cache.insert(atom, sym.clone());
+        sym
+    }
+
+    /// A value that isn't analysed any further, which may be null, and is
+    /// known to lie in `bounds` if it's an integer.
+    fn opaque(&mut self, atom: Atom, bounds: Interval) -> Sym {
+        match self.local_type(atom) {
+            Some(Type::PrimBool) | Some(Type::PrimString) | Some(Type::PrimChar) => Sym::Unknown,
+            _ => {
+                self.bounds.insert(atom, bounds);
+                self.nullable.insert(atom);
+                Sym::Int(Linear::atom(atom))
+            }
+        }
+    }
+
+    /// A value of a known type that isn't analysed any further, and isn't null.
+    fn not_null(&mut self, atom: Atom) -> Sym {
+        match self.local_type(atom) {
+            Some(Type::PrimBool) => Sym::Bool(None),
+            Some(Type::PrimString) => Sym::Str,
+            Some(Type::PrimInt) => {
+                self.bounds.insert(atom, Interval::ANY);
+                Sym::Int(Linear::atom(atom))
+            }
+            _ => Sym::Unknown,
+        }
+    }
+
+    /// An integer known not to be null, in `bounds`.
+    fn int_in(&mut self, atom: Atom, bounds: Interval) -> Sym {
+        self.bounds.insert(atom, bounds);
+        Sym::Int(Linear::atom(atom))
+    }
+
+    fn local_type(&self, (depth, lr): Atom) -> Option<Type> {
+        match lr {
+            LacunaryRef::InstanciatedRef(_) => None,
+            _ => self.frames[depth].type_of(&lr),
+        }
+    }
+
+    fn may_be_null(&self, l: &Linear) -> bool {
+        l.terms.keys().any(|atom| self.nullable.contains(atom))
+    }
+
+    fn interval(&self, l: &Linear) -> Interval {
+        let mut result = Interval::exactly(l.constant);
+        for (atom, coefficient) in l.terms.iter() {
+            let b = self.bounds.get(atom).copied().unwrap_or(Interval::ANY);
+            let (lo, hi) = if *coefficient > 0 { (b.lo, b.hi) } else { (b.hi, b.lo) };
+            result.lo = result.lo.and_then(|r| lo.and_then(|x| x.checked_mul(*coefficient)?.checked_add(r)));
+            result.hi = result.hi.and_then(|r| hi.and_then(|x| x.checked_mul(*coefficient)?.checked_add(r)));
+        }
+        result
+    }
+
+    /// The name of the builtin fragment referenced, if it is one.
+    fn builtin_name(&self, atom: &Atom) -> Option<String> {
+        match atom.1 {
+            LacunaryRef::InstanciatedRef(_) => self.frames[0]
+                .values_by_name
+                .iter()
+                .find(|(_, v)| **v == atom.1)
+                .map(|(name, _)| name.clone()),
+            _ => None,
+        }
+    }
+
+    fn eval_operation(&mut self, atom: Atom, operation: &Operation<LacunaryRef>) -> Sym {
+        use Operation::*;
+        let depth = atom.0;
+
+        match operation {
+            Const(VarType::Int(i)) => Sym::Int(Linear::constant(*i)),
+            Const(VarType::Bool(b)) => Sym::Bool(Some(*b)),
+            Const(v) => Sym::Value(v.clone()),
+            BinaryOp(a, b, op) => {
+                let (a, b) = (self.eval(depth, a), self.eval(depth, b));
+                self.eval_binary(atom, a, b, *op)
+            }
+            IfElse(g, b, e) => match self.eval(depth, g) {
+                Sym::Bool(Some(true)) => self.eval(depth, b),
+                Sym::Bool(Some(false)) => self.eval(depth, e),
+                // A null guard gives null.
+                Sym::Bool(None) => match (self.eval(depth, b), self.eval(depth, e)) {
+                    (x, y) if x == y => x,
+                    (Sym::Int(x), Sym::Int(y)) => {
+                        let bounds = self.interval(&x).hull(self.interval(&y));
+                        if self.may_be_null(&x) || self.may_be_null(&y) {
+                            self.opaque(atom, bounds)
+                        } else {
+                            self.int_in(atom, bounds)
+                        }
+                    }
+                    (Sym::Bool(_), Sym::Bool(_)) => Sym::Bool(None),
+                    _ => self.opaque(atom, Interval::ANY),
+                },
+                _ => self.opaque(atom, Interval::ANY),
+            },
+            StringOp(op, args) => self.eval_string_op(atom, *op, args),
+            Check(_, _, _, output) => self.eval(depth, output),
+            ApplyFragment(f, args) => {
+                let f = self.resolve(depth, f);
+                let op = self
+                    .builtin_name(&f)
+                    .and_then(|name| crate::program::StringOp::ALL.iter().find(|op| op.name() == name).copied());
+                match op {
+                    Some(op) => self.eval_string_op(atom, op, args),
+                    None => self.opaque(atom, Interval::ANY),
+                }
+            }
+            _ => self.opaque(atom, Interval::ANY),
+        }
+    }
+
+    /// Whether an argument is certainly a string, as string operations give
+    /// null for anything else.
+    fn is_str(&mut self, depth: usize, arg: &LacunaryRef) -> bool {
+        matches!(self.eval(depth, arg), Sym::Str | Sym::Value(VarType::Str(_)))
+    }
+
+    fn eval_string_op(&mut self, atom: Atom, op: StringOp, args: &[LacunaryRef]) -> Sym {
+        // Nothing in memory is longer than isize::MAX.
+        let max_len = isize::MAX as i64;
+
+        match op {
+            StringOp::Len => {
+                let bounds = Interval { lo: Some(0), hi: Some(max_len) };
+                match args.first().map(|a| self.eval(atom.0, a)) {
+                    Some(Sym::Value(VarType::Str(s))) => Sym::Int(Linear::constant(s.chars().count() as i64)),
+                    Some(Sym::Value(VarType::Vector(v))) => Sym::Int(Linear::constant(v.len() as i64)),
+                    Some(Sym::Str) => self.int_in(atom, bounds),
+                    _ => self.opaque(atom, bounds),
+                }
+            }
+            StringOp::Find => {
+                let bounds = Interval { lo: Some(-1), hi: Some(max_len - 1) };
+                if self.is_str(atom.0, &args[0]) && self.is_str(atom.0, &args[1]) {
+                    self.int_in(atom, bounds)
+                } else {
+                    self.opaque(atom, bounds)
+                }
+            }
+            StringOp::Clamp => {
+                let args: Vec<Sym> = args.iter().map(|a| self.eval(atom.0, a)).collect();
+                let bounds: Vec<Interval> = args[1..]
+                    .iter()
+                    .map(|a| match a {
+                        Sym::Int(l) => self.interval(l),
+                        _ => Interval::ANY,
+                    })
+                    .collect();
+                // The result is at most the upper limit, and at least the
+                // lower one unless the limits are the wrong way around.
+                let (lo, hi) = (bounds[0], bounds[1]);
+                let lo = lo.lo.and_then(|a| hi.lo.map(|b| a.min(b)));
+                let bounds = Interval { lo, hi: hi.hi };
+
+                let not_null = args.iter().all(|a| match a {
+                    Sym::Int(l) => !self.may_be_null(l),
+                    _ => false,
+                });
+                if not_null {
+                    self.int_in(atom, bounds)
+                } else {
+                    self.opaque(atom, bounds)
+                }
+            }
+            _ => self.opaque(atom, Interval::ANY),
+        }
+    }
+
+    fn eval_binary(&mut self, atom: Atom, a: Sym, b: Sym, op: BinaryOp) -> Sym {
+        match (op, a, b) {
+            // The sum is null if it overflows, so it's only kept symbolic
+            // when its range shows it can't.
+            (BinaryOp::Sum, Sym::Int(a), Sym::Int(b)) => match a.add_scaled(&b, 1) {
+                Some(sum) => {
+                    let iv = self.interval(&sum);
+                    if self.may_be_null(&a) || self.may_be_null(&b) || iv.lo.is_none() || iv.hi.is_none() {
+                        self.opaque(atom, iv)
+                    } else {
+                        Sym::Int(sum)
+                    }
+                }
+                None => self.opaque(atom, Interval::ANY),
+            },
+            (BinaryOp::Concat, Sym::Value(VarType::Str(a)), Sym::Value(VarType::Str(b))) => {
+                Sym::Value(VarType::from_string(&[&a[..], &b[..]].concat()))
+            }
+            (BinaryOp::Lt, Sym::Int(a), Sym::Int(b)) => self.compare(&b, &a, 1),
+            (BinaryOp::Leq, Sym::Int(a), Sym::Int(b)) => self.compare(&b, &a, 0),
+            (BinaryOp::Gt, Sym::Int(a), Sym::Int(b)) => self.compare(&a, &b, 1),
+            (BinaryOp::Geq, Sym::Int(a), Sym::Int(b)) => self.compare(&a, &b, 0),
+            (BinaryOp::Eq, Sym::Int(a), Sym::Int(b)) if self.either_null(&a, &b) => Sym::Bool(None),
+            (BinaryOp::Eq, Sym::Int(a), Sym::Int(b)) => match a.add_scaled(&b, -1) {
+                Some(diff) => {
+                    let iv = self.interval(&diff);
+                    let result = if iv == Interval::exactly(0) {
+                        Some(true)
+                    } else if iv.lo.map_or(false, |lo| lo > 0) || iv.hi.map_or(false, |hi| hi < 0) {
+                        Some(false)
+                    } else {
+                        None
+                    };
+                    self.reason = Some(format!("the difference between the two sides lies in {}", iv));
+                    Sym::Bool(result)
+                }
+                None => Sym::Bool(None),
+            },
+            (BinaryOp::Eq, Sym::Bool(Some(a)), Sym::Bool(Some(b))) => Sym::Bool(Some(a == b)),
+            (op, Sym::Value(a), Sym::Value(b)) => {
+                let result = match op {
+                    BinaryOp::Eq => a == b,
+                    BinaryOp::Lt => a < b,
+                    BinaryOp::Leq => a <= b,
+                    BinaryOp::Gt => a > b,
+                    BinaryOp::Geq => a >= b,
+                    _ => return self.opaque(atom, Interval::ANY),
+                };
+                self.reason = Some("both sides are constant".to_string());
+                Sym::Bool(Some(result))
+            }
+            (BinaryOp::Eq, _, _)
+            | (BinaryOp::Lt, _, _)
+            | (BinaryOp::Leq, _, _)
+            | (BinaryOp::Gt, _, _)
+            | (BinaryOp::Geq, _, _) => Sym::Bool(None),
+            _ => self.opaque(atom, Interval::ANY),
+        }
+    }
+
+    /// Whether either side of a comparison may be null, in which case it
+    /// can't be decided, and explain so.
+    fn either_null(&mut self, a: &Linear, b: &Linear) -> bool {
+        let null = self.may_be_null(a) || self.may_be_null(b);
+        if null {
+            self.reason = Some("one of the sides may be null".to_string());
+        }
+        null
+    }
+
+    /// Whether `larger - smaller >= threshold`.
+    fn compare(&mut self, larger: &Linear, smaller: &Linear, threshold: i64) -> Sym {
+        if self.either_null(larger, smaller) {
+            return Sym::Bool(None);
+        }
+
+        let diff = match larger.add_scaled(smaller, -1) {
+            Some(diff) => diff,
+            None => return Sym::Bool(None),
+        };
+        let iv = self.interval(&diff);
+
+        let result = if iv.lo.map_or(false, |lo| lo >= threshold) {
+            Some(true)
+        } else if iv.hi.map_or(false, |hi| hi < threshold) {
+            Some(false)
+        } else {
+            None
+        };
+
+        self.reason = Some(format!(
+            "the difference between the two sides lies in {}, and must be at least {}",
+            iv, threshold
+        ));
+        Sym::Bool(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{build_value, check_assertion, prelude};
+    use crate::nom_parse::expression;
+    use crate::run::RuntimeEnv;
+    use nom::error::VerboseError;
+
+    /// Verify `claim` in a module with inputs `x : int` and `s : str`.
+    fn verify(claim: &str) -> Result<VerifyResult, &'static str> {
+        let mut re = RuntimeEnv::new();
+        let root = prelude(&mut re);
+        let mut env = root.derive_child("main".to_string());
+
+        for (index, (name, t)) in [("x", Type::PrimInt), ("s", Type::PrimString)].iter().enumerate() {
+            env.values_by_name
+                .insert(name.to_string(), LacunaryRef::InputRef { up: 0, index });
+            env.input_types.push(t.clone());
+        }
+
+        let claim = expression::<VerboseError<&str>>(claim).unwrap().1;
+        check_assertion(claim, &mut env).map(|(_, v)| v.result)
+    }
+
+    #[test]
+    fn test_constant_claims() {
+        assert_eq!(verify("(1 + 2) >= 3"), Ok(VerifyResult::True));
+        assert_eq!(verify("1 > 2"), Err("Assertion can never hold."));
+        assert_eq!(verify("\"a\" < \"b\""), Ok(VerifyResult::True));
+        assert_eq!(verify("1 + 2"), Err("Assertion must be a boolean."));
+    }
+
+    #[test]
+    fn test_symbolic_claims() {
+        assert_eq!(verify("let c = clamp(x, 0, 9); y = c + 1 in c < y"), Ok(VerifyResult::True));
+        assert_eq!(verify("let c = clamp(x, 0, 9); y = c + 1 in y == c"), Err("Assertion can never hold."));
+        assert_eq!(verify("x >= 0"), Ok(VerifyResult::Unsure));
+        assert_eq!(verify("(len(s)) >= 0"), Ok(VerifyResult::True));
+        assert_eq!(verify("let i = find(s, \"a\"); j = i + 1 in j >= 0"), Ok(VerifyResult::True));
+        assert_eq!(verify("let v = \"abc\" in 2 < (len(v))"), Ok(VerifyResult::True));
+        assert_eq!(verify("let v = \"abc\" in 3 < (len(v))"), Err("Assertion can never hold."));
+    }
+
+    #[test]
+    fn test_nulls_and_overflow() {
+        // x + 1 is null for the largest integer, and null is less than x.
+        assert_eq!(verify("let y = x + 1 in x < y"), Ok(VerifyResult::Unsure));
+        assert_eq!(verify("let p = parse_int(s); q = p + 1 in q > p"), Ok(VerifyResult::Unsure));
+        assert_eq!(verify("let p = parse_int(s) in p == p"), Ok(VerifyResult::Unsure));
+        assert_eq!(verify("clock >= 0"), Ok(VerifyResult::Unsure));
+        assert_eq!(verify("let c = clamp(clock, 0, 9) in c >= 0"), Ok(VerifyResult::Unsure));
+    }
+
+    #[test]
+    fn test_branches() {
+        assert_eq!(verify("let y = if x > 3 then 5 else 7 in y >= 5"), Ok(VerifyResult::True));
+        assert_eq!(verify("let y = if x > 3 then 5 else 7 in y > 5"), Ok(VerifyResult::Unsure));
+        assert_eq!(verify("let y = if 1 > 3 then 5 else 7 in y > 5"), Ok(VerifyResult::True));
+    }
+
+    fn index(container: &str, index: &str) -> VerifyResult {
+        let mut re = RuntimeEnv::new();
+        let root = prelude(&mut re);
+        let mut env = root.derive_child("main".to_string());
+        env.values_by_name
+            .insert("x".to_string(), LacunaryRef::InputRef { up: 0, index: 0 });
+        env.input_types.push(Type::PrimInt);
+
+        let parse = |e: &str| expression::<VerboseError<&str>>(e).unwrap().1;
+        let container = build_value(parse(container), &mut env).unwrap();
+        let index = build_value(parse(index), &mut env).unwrap();
+
+        verify_index(&env, &container, &index).result
+    }
+
+    #[test]
+    fn test_index_bounds() {
+        assert_eq!(index("\"abc\"", "2"), VerifyResult::True);
+        assert_eq!(index("\"abc\"", "3"), VerifyResult::False);
+        assert_eq!(index("\"abc\"", "-1"), VerifyResult::False);
+        assert_eq!(index("\"abc\"", "x"), VerifyResult::Unsure);
+        assert_eq!(index("\"abc\"", "clamp(x, 0, 2)"), VerifyResult::True);
+        assert_eq!(index("\"abc\"", "clamp(x, 0, 3)"), VerifyResult::Unsure);
+        assert_eq!(index("split(\"a,b\", \",\")", "0"), VerifyResult::Unsure);
+    }
+}